@@ -0,0 +1,195 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+    auth::{
+        extract::{Admin, Parent, Session},
+        Auth, Role,
+    },
+    state::AppState,
+    utils::audit::{outcome, AuditEvent, AuditKind, Client},
+};
+
+#[derive(Deserialize)]
+pub struct RegisterRequest {
+    pub name: String,
+    /// Client-side digest of the password, computed with `client_salt`.
+    pub password: String,
+    pub client_salt: String,
+}
+
+#[derive(Deserialize)]
+pub struct CreateUserRequest {
+    pub name: String,
+    pub password: String,
+    pub client_salt: String,
+    #[serde(default = "default_role")]
+    pub role: Role,
+    pub parent_id: Option<u64>,
+}
+
+const fn default_role() -> Role {
+    Role::Parent
+}
+
+#[derive(Deserialize)]
+pub struct ChangePasswordRequest {
+    pub old_password: String,
+    pub new_password: String,
+    /// Set when the client picked a new salt for `new_password`.
+    pub client_salt: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct UserProfile {
+    pub id: u64,
+    pub name: String,
+    pub role: Role,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<u64>,
+}
+
+impl From<&Auth> for UserProfile {
+    fn from(user: &Auth) -> Self {
+        Self {
+            id: user.id,
+            name: user.name.clone(),
+            role: user.role,
+            parent_id: user.parent_id,
+        }
+    }
+}
+
+pub async fn handle_register(
+    State(app): State<Arc<AppState>>,
+    Json(req): Json<RegisterRequest>,
+) -> impl IntoResponse {
+    info!("register request: {}", req.name);
+    if !app.config.get().server.registration_enabled {
+        return (StatusCode::FORBIDDEN, "Forbidden: registration is disabled").into_response();
+    }
+    create_user(app, req.name, req.password, req.client_salt, Role::Parent, None).await
+}
+
+pub async fn handle_create_user(
+    State(app): State<Arc<AppState>>,
+    Admin(admin): Admin,
+    Json(req): Json<CreateUserRequest>,
+) -> impl IntoResponse {
+    info!("user {} creates user {}", admin.id, req.name);
+    create_user(
+        app,
+        req.name,
+        req.password,
+        req.client_salt,
+        req.role,
+        req.parent_id,
+    )
+    .await
+}
+
+pub async fn handle_list_users(
+    State(app): State<Arc<AppState>>,
+    Admin(admin): Admin,
+) -> impl IntoResponse {
+    info!("user {} lists users", admin.id);
+    profiles(Auth::list(&app))
+}
+
+pub async fn handle_profile(user: Auth) -> Json<UserProfile> {
+    Json(UserProfile::from(&user))
+}
+
+pub async fn handle_create_child(
+    State(app): State<Arc<AppState>>,
+    Parent(parent): Parent,
+    Json(req): Json<RegisterRequest>,
+) -> impl IntoResponse {
+    info!("user {} creates child {}", parent.id, req.name);
+    create_user(
+        app,
+        req.name,
+        req.password,
+        req.client_salt,
+        Role::Child,
+        Some(parent.id),
+    )
+    .await
+}
+
+pub async fn handle_list_children(
+    State(app): State<Arc<AppState>>,
+    Parent(parent): Parent,
+) -> impl IntoResponse {
+    profiles(parent.children(&app))
+}
+
+fn profiles(users: anyhow::Result<Vec<Auth>>) -> Response {
+    match users {
+        Ok(users) => Json(users.iter().map(UserProfile::from).collect::<Vec<_>>()).into_response(),
+        Err(e) => {
+            tracing::error!("list users error: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response()
+        }
+    }
+}
+
+async fn create_user(
+    app: Arc<AppState>,
+    name: String,
+    password: String,
+    client_salt: String,
+    role: Role,
+    parent_id: Option<u64>,
+) -> Response {
+    let created = super::blocking(move || {
+        Auth::create(&app, name, &password, client_salt, role, parent_id)
+    });
+    match created.await {
+        Ok(user) => (StatusCode::CREATED, Json(UserProfile::from(&user))).into_response(),
+        Err(e) => {
+            info!("create user failed: {}", e);
+            (StatusCode::BAD_REQUEST, format!("Bad Request: {e}")).into_response()
+        }
+    }
+}
+
+pub async fn handle_change_password(
+    State(app): State<Arc<AppState>>,
+    Parent(mut user): Parent,
+    _: Session,
+    client: Client,
+    Json(req): Json<ChangePasswordRequest>,
+) -> impl IntoResponse {
+    info!("change password request: {}", user.id);
+    let (id, name) = (user.id, user.name.clone());
+    let result = {
+        let app = app.clone();
+        super::blocking(move || {
+            user.change_password(&app, &req.old_password, &req.new_password, req.client_salt)
+        })
+        .await
+    };
+    let event = AuditEvent::new(AuditKind::PasswordChanged, outcome(&result))
+        .actor(id, &name)
+        .client(&client);
+    match result {
+        Ok(()) => {
+            event.record(&app.audit);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => {
+            info!("change password failed: {}", e);
+            event.detail(e.to_string()).record(&app.audit);
+            (StatusCode::BAD_REQUEST, format!("Bad Request: {e}")).into_response()
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+    auth::{
+        api_key::{ApiKey, Scope},
+        extract::{Identity, Session},
+    },
+    state::AppState,
+    utils::audit::{AuditEvent, AuditKind, Client},
+};
+
+/// Longest lifetime a key can be created with, ten years.
+const MAX_EXPIRES_IN_DAYS: i64 = 10 * 366;
+
+#[derive(Deserialize)]
+pub struct CreateApiKeyRequest {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    /// Lifetime in days, keys without one never expire.
+    pub expires_in_days: Option<i64>,
+}
+
+/// Expiry timestamp of a key created at `now` living `days`, `None` unless
+/// `days` is within `1..=MAX_EXPIRES_IN_DAYS`.
+fn expiry(now: i64, days: i64) -> Option<i64> {
+    if !(1..=MAX_EXPIRES_IN_DAYS).contains(&days) {
+        return None;
+    }
+    days.checked_mul(24 * 60 * 60).and_then(|secs| now.checked_add(secs))
+}
+
+/// A key as shown to its owner. `key` is only set in the response that
+/// created it.
+#[derive(Serialize)]
+pub struct ApiKeyView {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub created: i64,
+    pub expires: Option<i64>,
+    pub last_used: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+}
+
+impl From<ApiKey> for ApiKeyView {
+    fn from(key: ApiKey) -> Self {
+        Self {
+            id: key.id,
+            name: key.name,
+            scopes: key.scopes,
+            created: key.created,
+            expires: key.expires,
+            last_used: key.last_used,
+            key: None,
+        }
+    }
+}
+
+pub async fn handle_create_api_key(
+    State(app): State<Arc<AppState>>,
+    Session(Identity { user, .. }): Session,
+    client: Client,
+    Json(req): Json<CreateApiKeyRequest>,
+) -> Response {
+    info!("create api key request: {} {:?}", user.id, req.scopes);
+    let name = req.name.trim();
+    if name.is_empty() || req.scopes.is_empty() {
+        return (StatusCode::BAD_REQUEST, "Bad Request: name and scopes are required")
+            .into_response();
+    }
+    let expires = match req.expires_in_days {
+        Some(days) => match expiry(chrono::Utc::now().timestamp(), days) {
+            Some(expires) => Some(expires),
+            None => {
+                return (StatusCode::BAD_REQUEST, "Bad Request: invalid expiry").into_response()
+            }
+        },
+        None => None,
+    };
+    match user.create_api_key(&app, name.to_owned(), req.scopes, expires) {
+        Ok((key, secret)) => {
+            AuditEvent::success(AuditKind::ApiKeyCreated)
+                .actor(user.id, &user.name)
+                .client(&client)
+                .detail(format!("key {} scopes {:?}", key.id, key.scopes))
+                .record(&app.audit);
+            let view = ApiKeyView {
+                key: Some(secret),
+                ..key.into()
+            };
+            (StatusCode::CREATED, Json(view)).into_response()
+        }
+        Err(e) => {
+            tracing::error!("create api key error: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response()
+        }
+    }
+}
+
+pub async fn handle_list_api_keys(
+    State(app): State<Arc<AppState>>,
+    Session(Identity { user, .. }): Session,
+) -> impl IntoResponse {
+    let keys = user
+        .api_keys(&app)
+        .into_iter()
+        .map(ApiKeyView::from)
+        .collect::<Vec<_>>();
+    Json(keys)
+}
+
+pub async fn handle_revoke_api_key(
+    State(app): State<Arc<AppState>>,
+    Session(Identity { user, .. }): Session,
+    client: Client,
+    Path(id): Path<String>,
+) -> Response {
+    match user.revoke_api_key(&app, &id) {
+        Ok(true) => {
+            AuditEvent::success(AuditKind::ApiKeyRevoked)
+                .actor(user.id, &user.name)
+                .client(&client)
+                .detail(format!("key {id}"))
+                .record(&app.audit);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(false) => (StatusCode::NOT_FOUND, "Not Found").into_response(),
+        Err(e) => {
+            tracing::error!("revoke api key error: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expiry_is_bounded() {
+        assert_eq!(expiry(100, 1), Some(100 + 24 * 60 * 60));
+        assert!(expiry(100, MAX_EXPIRES_IN_DAYS).is_some());
+        assert_eq!(expiry(100, 0), None);
+        assert_eq!(expiry(100, MAX_EXPIRES_IN_DAYS + 1), None);
+        assert_eq!(expiry(100, i64::MAX), None);
+        assert_eq!(expiry(i64::MAX, 1), None);
+    }
+}
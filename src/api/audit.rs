@@ -0,0 +1,27 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use crate::{auth::extract::Admin, state::AppState, utils::audit::AuditQuery};
+
+/// Audit events matching the query string, newest first, e.g.
+/// `?uid=3&since=1700000000&kind=login&outcome=failure&limit=50&offset=50`.
+pub async fn handle_query_audit(
+    State(app): State<Arc<AppState>>,
+    Admin(_): Admin,
+    Query(query): Query<AuditQuery>,
+) -> Response {
+    // The file log scans the whole file, keep that off the async workers.
+    match super::blocking(move || app.audit.query(&query)).await {
+        Ok(events) => Json(events).into_response(),
+        Err(e) => {
+            tracing::error!("query audit log error: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response()
+        }
+    }
+}
@@ -0,0 +1,46 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use tracing::info;
+
+use crate::{
+    auth::extract::Admin,
+    config::ReloadStatus,
+    utils::audit::Client,
+    ws::state::WsState,
+};
+
+/// When the configuration in effect was loaded and how the last reload went.
+pub async fn handle_config_status(
+    Admin(_): Admin,
+    State(state): State<Arc<WsState>>,
+) -> Json<ReloadStatus> {
+    Json(state.config.status())
+}
+
+/// Reloads the configuration like SIGHUP does. Answers with the new status,
+/// as `422` when the configuration was rejected.
+pub async fn handle_config_reload(
+    Admin(admin): Admin,
+    State(state): State<Arc<WsState>>,
+    client: Client,
+) -> Response {
+    info!("user {} reloads the configuration", admin.id);
+    let attempt = state.config.reload("api");
+    attempt
+        .audit_event()
+        .actor(admin.id, &admin.name)
+        .client(&client)
+        .record(&state.app.audit);
+    let status = if attempt.ok {
+        StatusCode::OK
+    } else {
+        StatusCode::UNPROCESSABLE_ENTITY
+    };
+    (status, Json(state.config.status())).into_response()
+}
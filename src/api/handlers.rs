@@ -0,0 +1,40 @@
+use std::sync::Arc;
+
+use axum::{extract::State, Json};
+use serde::Serialize;
+
+use crate::{
+    auth::{Auth, Role},
+    handlers::Schema,
+    utils::event::EventType,
+    ws::state::WsState,
+};
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HandlerView {
+    pub event_type: EventType,
+    pub response_type: EventType,
+    pub required_role: Role,
+    pub schema: Schema,
+}
+
+/// Requests the caller may send to the server over the WebSocket.
+pub async fn handle_list_handlers(
+    user: Auth,
+    State(state): State<Arc<WsState>>,
+) -> Json<Vec<HandlerView>> {
+    let mut handlers = state
+        .handlers
+        .iter()
+        .filter(|handler| user.effective_role(&state.app).allows(handler.required_role()))
+        .map(|handler| HandlerView {
+            event_type: handler.event_type(),
+            response_type: handler.response_type(),
+            required_role: handler.required_role(),
+            schema: handler.schema(),
+        })
+        .collect::<Vec<_>>();
+    handlers.sort_by_key(|h| format!("{:?}", h.event_type));
+    Json(handlers)
+}
@@ -0,0 +1,263 @@
+use std::{net::IpAddr, sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Path, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use jsonwebtoken::jwk::JwkSet;
+use tracing::info;
+
+use crate::{
+    auth::{
+        self,
+        extract::{Admin, Identity, Session},
+        LoginResponse,
+    },
+    state::AppState,
+    utils::audit::{AuditEvent, AuditKind, Client, Outcome},
+    ws::{self, state::WsState},
+};
+
+pub async fn handle_login(
+    State(app): State<Arc<AppState>>,
+    client: Client,
+    req: axum::Json<auth::LoginRequest>,
+) -> impl IntoResponse {
+    info!("login request: {} from {}", req.name, client.addr);
+    let ip = client.addr.ip();
+    let audit = |outcome| {
+        AuditEvent::new(AuditKind::Login, outcome)
+            .name(&*req.name)
+            .client(&client)
+    };
+    if let Some(wait) = app.throttle.check(&req.name, ip) {
+        info!("login locked out: {} from {}", req.name, client.addr);
+        audit(Outcome::Failure).detail("locked out").record(&app.audit);
+        return too_many_attempts(wait);
+    }
+    let (name, password) = (req.name.clone(), req.password.clone());
+    let login = {
+        let app = app.clone();
+        super::blocking(move || auth::Auth::login_by_name(&app, &name, &password)).await
+    };
+    match login {
+        Ok((uid, resp)) => {
+            let event = audit(Outcome::Success).uid(uid);
+            match resp {
+                LoginResponse::Tokens(_) => {
+                    app.throttle.record_success(&req.name);
+                    event.record(&app.audit)
+                }
+                // Failed codes stay counted until the challenge is passed.
+                LoginResponse::TwoFactor(_) => {
+                    event.detail("second factor required").record(&app.audit)
+                }
+            }
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+        Err(e) => {
+            app.throttle.record_failure(&req.name, ip);
+            audit(Outcome::Failure).detail(e.to_string()).record(&app.audit);
+            (
+                StatusCode::UNAUTHORIZED,
+                "Unauthorized: invalid username or password",
+            )
+                .into_response()
+        }
+    }
+}
+
+pub(super) fn too_many_attempts(wait: Duration) -> Response {
+    // Round up so that retrying after `Retry-After` never hits the lockout.
+    let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, secs.to_string())],
+        "Too Many Requests: too many failed login attempts",
+    )
+        .into_response()
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct UnlockRequest {
+    pub name: Option<String>,
+    pub ip: Option<IpAddr>,
+}
+
+pub async fn handle_unlock(
+    State(app): State<Arc<AppState>>,
+    Admin(admin): Admin,
+    client: Client,
+    Json(req): Json<UnlockRequest>,
+) -> impl IntoResponse {
+    info!(
+        "user {} unlocks login for {:?} {:?}",
+        admin.id, req.name, req.ip
+    );
+    if app.throttle.unlock(req.name.as_deref(), req.ip) {
+        AuditEvent::success(AuditKind::Unlock)
+            .actor(admin.id, &admin.name)
+            .client(&client)
+            .detail(format!("name {:?} ip {:?}", req.name, req.ip))
+            .record(&app.audit);
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+#[derive(serde::Deserialize, Debug, serde::Serialize)]
+pub struct ClientSaltRequest {
+    pub salt: String,
+}
+
+pub async fn handle_client_salt(
+    State(app): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Json<ClientSaltRequest> {
+    info!("client salt request: {:?}", name);
+    Json(ClientSaltRequest {
+        salt: auth::client_salt(&app, &name),
+    })
+}
+
+#[derive(serde::Deserialize, Debug, serde::Serialize)]
+pub struct RefreshTokenRequest {
+    pub refresh_token: String,
+}
+pub async fn handle_refresh_token(
+    State(app): State<Arc<AppState>>,
+    client: Client,
+    Json(RefreshTokenRequest { refresh_token }): Json<RefreshTokenRequest>,
+) -> impl IntoResponse {
+    info!("refresh token request");
+    let mut event = AuditEvent::success(AuditKind::Refresh).client(&client);
+    let resp = auth::Auth::from_refresh_token(&app, &refresh_token).and_then(|(db_user, claims)| {
+        event = event.clone().actor(db_user.id, &db_user.name);
+        db_user.refresh_access_token(&app, &claims)
+    });
+    match resp {
+        Ok(resp) => {
+            event.record(&app.audit);
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+        Err(e) => {
+            info!("refresh token failed: {}", e);
+            event.outcome = Outcome::Failure;
+            event.detail(e.to_string()).record(&app.audit);
+            (
+                StatusCode::UNAUTHORIZED,
+                "Unauthorized: invalid refresh token",
+            )
+                .into_response()
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Default)]
+pub struct LogoutRequest {
+    /// Revoke every session of the user instead of only the current one.
+    #[serde(default)]
+    pub all: bool,
+}
+
+pub async fn handle_logout(
+    State(state): State<Arc<WsState>>,
+    Session(Identity { user, claims }): Session,
+    client: Client,
+    req: Option<Json<LogoutRequest>>,
+) -> impl IntoResponse {
+    let Json(req) = req.unwrap_or_default();
+    info!("logout request: {} all={}", user.id, req.all);
+    if let Err(e) = auth::Auth::logout(&state.app, &claims, req.all) {
+        tracing::error!("logout error: {:?}", e);
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response();
+    }
+    // Other logins keep their sockets unless all of them end.
+    let fid = (!req.all).then_some(claims.fid.as_str());
+    let closed = state.close_user_sessions(user.id, fid, ws::CLOSE_LOGGED_OUT, "logged out");
+    info!("user {} logged out, closed {} sockets", user.id, closed);
+    let event = AuditEvent::success(AuditKind::Logout)
+        .actor(user.id, &user.name)
+        .client(&client);
+    if req.all {
+        event.detail("all sessions").record(&state.app.audit);
+    } else {
+        event.record(&state.app.audit);
+    }
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// Public keys that verify access tokens, for other services. Empty while
+/// tokens are signed with a shared secret.
+pub async fn handle_jwks(State(app): State<Arc<AppState>>) -> Json<JwkSet> {
+    Json(app.keys.access.jwks())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api::two_factor::{self, ChallengeRequest},
+        auth::{throttle::ThrottleConfig, totp::TotpState, Auth, LoginRequest, Role},
+    };
+
+    fn client() -> Client {
+        Client {
+            addr: "127.0.0.1:4000".parse().unwrap(),
+            user_agent: None,
+        }
+    }
+
+    async fn challenge_token(app: &Arc<AppState>) -> String {
+        let req = LoginRequest {
+            name: "ann".into(),
+            password: "pw".into(),
+        };
+        let resp = handle_login(State(app.clone()), client(), Json(req))
+            .await
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        body["challenge_token"].as_str().unwrap().to_owned()
+    }
+
+    #[tokio::test]
+    async fn test_password_step_keeps_second_factor_failures() {
+        let app = Arc::new(AppState::in_temp_dir());
+        app.throttle.set_config(ThrottleConfig {
+            free_attempts: 1,
+            base_delay: Duration::from_secs(60),
+            max_delay: Duration::from_secs(60),
+            window: Duration::from_secs(60),
+        });
+        let salt = uuid::Uuid::new_v4().to_string();
+        let user = Auth::create(&app, "ann".into(), "pw", salt, Role::Parent, None).unwrap();
+        let totp = TotpState {
+            secret: "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ".into(),
+            enabled: true,
+            ..Default::default()
+        };
+        app.users
+            .update_with(user.id, &mut |u| {
+                u.totp = Some(totp.clone());
+                Ok(())
+            })
+            .unwrap();
+
+        for _ in 0..2 {
+            let req = ChallengeRequest {
+                challenge_token: challenge_token(&app).await,
+                code: "wrong".into(),
+            };
+            let resp = two_factor::handle_login_challenge(State(app.clone()), client(), Json(req))
+                .await
+                .into_response();
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        }
+        assert!(app.throttle.check("ann", client().addr.ip()).is_some());
+    }
+}
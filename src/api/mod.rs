@@ -0,0 +1,91 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::FromRef,
+    routing::{delete, get, post},
+    Router,
+};
+
+use crate::{state::AppState, ws::state::WsState};
+
+mod account;
+mod api_key;
+mod audit;
+mod config;
+mod handlers;
+mod login;
+mod oidc;
+mod session;
+mod two_factor;
+
+pub use login::handle_jwks;
+
+/// Runs `f` on the blocking pool. For work that takes long enough to stall
+/// the async workers, like Argon2 hashing and file scans.
+pub(super) async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> anyhow::Result<T> + Send + 'static,
+) -> anyhow::Result<T> {
+    tokio::task::spawn_blocking(f).await.map_err(anyhow::Error::from).and_then(|r| r)
+}
+
+/// State of the REST routes. Handlers take the part they need.
+#[derive(Clone)]
+pub struct ApiState {
+    pub app: Arc<AppState>,
+    pub ws: Arc<WsState>,
+}
+
+impl FromRef<ApiState> for Arc<AppState> {
+    fn from_ref(state: &ApiState) -> Self {
+        state.app.clone()
+    }
+}
+
+impl FromRef<ApiState> for Arc<WsState> {
+    fn from_ref(state: &ApiState) -> Self {
+        state.ws.clone()
+    }
+}
+
+pub fn router(state: ApiState) -> Router {
+    Router::new()
+        .route("/login", post(login::handle_login))
+        .route("/login/2fa", post(two_factor::handle_login_challenge))
+        .route("/oidc/login", get(oidc::handle_oidc_login))
+        .route("/oidc/callback", get(oidc::handle_oidc_callback))
+        .route("/refresh_token", post(login::handle_refresh_token))
+        .route("/logout", post(login::handle_logout))
+        .route("/:user/salt", get(login::handle_client_salt))
+        .route("/register", post(account::handle_register))
+        .route("/password", post(account::handle_change_password))
+        .route("/2fa/enroll", post(two_factor::handle_enroll))
+        .route("/2fa/confirm", post(two_factor::handle_confirm))
+        .route("/2fa/disable", post(two_factor::handle_disable))
+        .route("/profile", get(account::handle_profile))
+        .route("/handlers", get(handlers::handle_list_handlers))
+        .route(
+            "/keys",
+            get(api_key::handle_list_api_keys).post(api_key::handle_create_api_key),
+        )
+        .route("/keys/:id", delete(api_key::handle_revoke_api_key))
+        .route("/sessions", get(session::handle_list_sessions))
+        .route("/sessions/:uuid", delete(session::handle_close_session))
+        .route(
+            "/children",
+            get(account::handle_list_children).post(account::handle_create_child),
+        )
+        .route(
+            "/admin/users",
+            get(account::handle_list_users).post(account::handle_create_user),
+        )
+        .route("/admin/unlock", post(login::handle_unlock))
+        .route("/admin/audit", get(audit::handle_query_audit))
+        .route("/admin/sessions", get(session::handle_list_all_sessions))
+        .route("/admin/config", get(config::handle_config_status))
+        .route("/admin/config/reload", post(config::handle_config_reload))
+        .route(
+            "/admin/oidc/links",
+            get(oidc::handle_list_oidc_links).post(oidc::handle_link_oidc_subject),
+        )
+        .with_state(state)
+}
@@ -0,0 +1,202 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+    Json,
+};
+use serde::Deserialize;
+use tracing::info;
+
+use crate::{
+    auth::{
+        extract::Admin,
+        oidc::OidcLink,
+        Auth, LoginResponse,
+    },
+    state::AppState,
+    utils::audit::{outcome, AuditEvent, AuditKind, Client, Outcome},
+    ws::state::WsState,
+};
+
+fn not_configured() -> Response {
+    (StatusCode::NOT_FOUND, "Not Found: single sign-on is not configured").into_response()
+}
+
+/// Binds a login to the browser that started it, so that nobody can make a
+/// victim complete a login into the attacker's account.
+const STATE_COOKIE: &str = "oidc_state";
+
+/// Sends the browser to the identity provider.
+pub async fn handle_oidc_login(State(state): State<Arc<WsState>>) -> Response {
+    let Some(oidc) = state.oidc.as_ref() else {
+        return not_configured();
+    };
+    match oidc.authorization_url().await {
+        Ok((url, state)) => {
+            let secure = if oidc.config.redirect_uri.starts_with("https:") {
+                "; Secure"
+            } else {
+                ""
+            };
+            let cookie = format!(
+                "{STATE_COOKIE}={state}; Path=/api/oidc; Max-Age=600; HttpOnly; SameSite=Lax{secure}"
+            );
+            ([(header::SET_COOKIE, cookie)], Redirect::to(&url)).into_response()
+        }
+        Err(e) => {
+            tracing::error!("oidc login error: {:?}", e);
+            (StatusCode::BAD_GATEWAY, "Bad Gateway: identity provider unavailable")
+                .into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CallbackQuery {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Where the provider sends the browser back. Answers like a password login,
+/// with the token pair or a `/api/login/2fa` challenge for TOTP users, either
+/// as JSON or in the fragment of `oidc.post_login_redirect`.
+pub async fn handle_oidc_callback(
+    State(ws): State<Arc<WsState>>,
+    client: Client,
+    headers: HeaderMap,
+    Query(query): Query<CallbackQuery>,
+) -> Response {
+    let Some(oidc) = ws.oidc.as_ref() else {
+        return not_configured();
+    };
+    let app = &ws.app;
+    let audit = AuditEvent::failure(AuditKind::Login).client(&client);
+    let (Some(code), Some(state)) = (query.code, query.state) else {
+        let error = query.error.unwrap_or_else(|| "missing code".into());
+        audit.detail(format!("oidc: {error}")).record(&app.audit);
+        return (StatusCode::UNAUTHORIZED, "Unauthorized: single sign-on failed").into_response();
+    };
+    if state_cookie(&headers) != Some(state.as_str()) {
+        audit.detail("oidc: state cookie mismatch").record(&app.audit);
+        return (StatusCode::UNAUTHORIZED, "Unauthorized: single sign-on failed").into_response();
+    }
+    let claims = match oidc.exchange(&code, &state).await {
+        Ok(claims) => claims,
+        Err(e) => {
+            info!("oidc callback failed: {:?}", e);
+            audit.detail(format!("oidc: {e}")).record(&app.audit);
+            return (StatusCode::UNAUTHORIZED, "Unauthorized: single sign-on failed")
+                .into_response();
+        }
+    };
+    let sub = claims.sub.clone();
+    let audit = audit.detail(format!("oidc subject {sub}"));
+    // A new user gets a password hash, keep that off the async workers.
+    let local_user = {
+        let ws = ws.clone();
+        super::blocking(move || match &ws.oidc {
+            Some(oidc) => oidc.local_user(&ws.app, &claims),
+            None => unreachable!("checked above"),
+        })
+    };
+    let user = match local_user.await {
+        Ok(user) => user,
+        Err(e) => {
+            info!("oidc login rejected: {}", e);
+            audit.record(&app.audit);
+            return (StatusCode::FORBIDDEN, "Forbidden: no account for this identity")
+                .into_response();
+        }
+    };
+    // The provider's own MFA is not trusted, TOTP users still need a code.
+    let resp = match user.login_response(app) {
+        Ok(resp) => resp,
+        Err(e) => {
+            tracing::error!("oidc token error: {:?}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response();
+        }
+    };
+    let audit = match resp {
+        LoginResponse::Tokens(_) => audit,
+        LoginResponse::TwoFactor(_) => {
+            audit.detail(format!("oidc subject {sub}, second factor required"))
+        }
+    };
+    AuditEvent {
+        outcome: Outcome::Success,
+        ..audit.actor(user.id, &user.name)
+    }
+    .record(&app.audit);
+    match &oidc.config.post_login_redirect {
+        Some(target) => {
+            // The fragment never reaches servers or their access logs.
+            let fragment = match &resp {
+                LoginResponse::Tokens(tokens) => format!(
+                    "access_token={}&refresh_token={}",
+                    tokens.access_token, tokens.refresh_token
+                ),
+                LoginResponse::TwoFactor(challenge) => {
+                    format!("challenge_token={}", challenge.challenge_token)
+                }
+            };
+            Redirect::to(&format!("{target}#{fragment}")).into_response()
+        }
+        None => (StatusCode::OK, Json(resp)).into_response(),
+    }
+}
+
+fn state_cookie(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .find_map(|c| c.trim().strip_prefix(STATE_COOKIE)?.strip_prefix('='))
+}
+
+#[derive(Deserialize)]
+pub struct LinkRequest {
+    pub subject: String,
+    pub uid: u64,
+}
+
+pub async fn handle_list_oidc_links(
+    State(app): State<Arc<AppState>>,
+    Admin(_): Admin,
+) -> Json<Vec<OidcLink>> {
+    Json(app.oidc_links.list())
+}
+
+/// Links a provider subject to an existing user, e.g. to move an account
+/// that used to sign in with a password over to single sign-on.
+pub async fn handle_link_oidc_subject(
+    Admin(admin): Admin,
+    State(state): State<Arc<WsState>>,
+    client: Client,
+    Json(req): Json<LinkRequest>,
+) -> Response {
+    let Some(oidc) = state.oidc.as_ref() else {
+        return not_configured();
+    };
+    let app = &state.app;
+    if Auth::new_by_id(app, req.uid).is_err() {
+        return (StatusCode::NOT_FOUND, "Not Found: no such user").into_response();
+    }
+    info!("user {} links oidc subject {} to user {}", admin.id, req.subject, req.uid);
+    let result = app.oidc_links.link(&oidc.config.issuer, &req.subject, req.uid);
+    AuditEvent::new(AuditKind::OidcLinked, outcome(&result))
+        .actor(admin.id, &admin.name)
+        .client(&client)
+        .detail(format!("oidc subject {} to user {}", req.subject, req.uid))
+        .record(&app.audit);
+    match result {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => {
+            tracing::error!("link oidc subject error: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response()
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use std::sync::{atomic::Ordering, Arc};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{
+    auth::{
+        api_key,
+        extract::{Admin, Identity, Session},
+        Auth,
+    },
+    utils::audit::{AuditEvent, AuditKind, Client},
+    ws::{self, state::WsState, Peer},
+};
+
+#[derive(Serialize)]
+pub struct SessionView {
+    pub uuid: String,
+    pub user_agent: String,
+    pub ip: String,
+    pub connected_at: i64,
+    pub last_activity: i64,
+    /// Id of the API key the socket connected with, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+    /// Whether the socket belongs to the same login as the request.
+    pub current: bool,
+    /// Events waiting to be written to the socket.
+    pub queued: usize,
+    /// Events discarded because the queue was full.
+    pub dropped: u64,
+}
+
+impl SessionView {
+    /// `caller_fid` is the token family of the request.
+    fn new(uuid: &Uuid, peer: &Peer, caller_fid: &str) -> Self {
+        let session = &peer.session;
+        let claims = session.claims.borrow();
+        Self {
+            uuid: uuid.to_string(),
+            user_agent: session.user_agent.clone(),
+            ip: session.addr.ip().to_string(),
+            connected_at: session.connected_at,
+            last_activity: session.last_activity.load(Ordering::Relaxed),
+            api_key: api_key::key_id(&claims).map(str::to_owned),
+            current: claims.fid == caller_fid,
+            queued: peer.sender.len(),
+            dropped: peer.sender.dropped(),
+        }
+    }
+}
+
+pub async fn handle_list_sessions(
+    State(state): State<Arc<WsState>>,
+    identity: Identity,
+) -> impl IntoResponse {
+    let mut sessions = state
+        .sessions(identity.user.id)
+        .iter()
+        .map(|(uuid, peer)| SessionView::new(uuid, peer, &identity.claims.fid))
+        .collect::<Vec<_>>();
+    sessions.sort_by_key(|s| s.connected_at);
+    Json(sessions)
+}
+
+#[derive(Serialize)]
+pub struct AdminSessionView {
+    pub uid: u64,
+    #[serde(flatten)]
+    pub session: SessionView,
+}
+
+/// Every open socket, fullest queue first.
+pub async fn handle_list_all_sessions(
+    Admin(_): Admin,
+    State(state): State<Arc<WsState>>,
+    identity: Identity,
+) -> impl IntoResponse {
+    let mut sessions = state
+        .all_sessions()
+        .iter()
+        .map(|(uuid, peer)| AdminSessionView {
+            uid: peer.session.uid,
+            session: SessionView::new(uuid, peer, &identity.claims.fid),
+        })
+        .collect::<Vec<_>>();
+    sessions.sort_by_key(|s| std::cmp::Reverse(s.session.queued));
+    Json(sessions)
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct CloseSessionQuery {
+    /// Also revoke the token family of the socket.
+    pub sign_out: bool,
+}
+
+/// Closes one socket of the caller. With `?sign_out=true` the login it belongs
+/// to is signed out as well, so the device cannot reconnect with the same
+/// tokens. Sockets of API keys are only closed, the key has to be revoked
+/// separately.
+pub async fn handle_close_session(
+    State(state): State<Arc<WsState>>,
+    Session(Identity { user, .. }): Session,
+    client: Client,
+    Path(uuid): Path<String>,
+    Query(query): Query<CloseSessionQuery>,
+) -> Response {
+    let Ok(uuid) = Uuid::parse_str(&uuid) else {
+        return (StatusCode::NOT_FOUND, "Not Found").into_response();
+    };
+    let reason = if query.sign_out {
+        "signed out remotely"
+    } else {
+        "closed remotely"
+    };
+    let Some(session) = state.close_session(user.id, &uuid, ws::CLOSE_SIGNED_OUT, reason) else {
+        return (StatusCode::NOT_FOUND, "Not Found").into_response();
+    };
+    let claims = session.claims.borrow().clone();
+    if query.sign_out && claims.scopes.is_none() {
+        if let Err(e) = Auth::logout(&state.app, &claims, false) {
+            tracing::error!("close session error: {:?}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response();
+        }
+    }
+    info!("user {} closed session {} from {}: {}", user.id, uuid, session.addr, reason);
+    AuditEvent::success(AuditKind::SessionClosed)
+        .actor(user.id, &user.name)
+        .client(&client)
+        .detail(format!("session {} from {}, {}", uuid, session.addr.ip(), reason))
+        .record(&state.app.audit);
+    StatusCode::NO_CONTENT.into_response()
+}
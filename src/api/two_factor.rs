@@ -0,0 +1,141 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+    auth::{
+        extract::{Enrolling, Parent, Session},
+        Auth,
+    },
+    state::AppState,
+    utils::audit::{outcome, AuditEvent, AuditKind, Client, Outcome},
+};
+
+#[derive(Deserialize)]
+pub struct CodeRequest {
+    /// Current TOTP code, or one of the recovery codes.
+    pub code: String,
+}
+
+#[derive(Deserialize)]
+pub struct DisableRequest {
+    pub password: String,
+    pub code: String,
+}
+
+#[derive(Deserialize)]
+pub struct ChallengeRequest {
+    pub challenge_token: String,
+    pub code: String,
+}
+
+#[derive(Serialize)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+pub async fn handle_enroll(
+    State(app): State<Arc<AppState>>,
+    Enrolling(mut user): Enrolling,
+) -> impl IntoResponse {
+    info!("totp enroll request: {}", user.id);
+    match user.enroll_totp(&app, &app.config.get().auth.totp_issuer) {
+        Ok(enrollment) => (StatusCode::OK, Json(enrollment)).into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, format!("Bad Request: {e}")).into_response(),
+    }
+}
+
+pub async fn handle_confirm(
+    State(app): State<Arc<AppState>>,
+    Enrolling(mut user): Enrolling,
+    client: Client,
+    Json(req): Json<CodeRequest>,
+) -> impl IntoResponse {
+    info!("totp confirm request: {}", user.id);
+    match user.confirm_totp(&app, &req.code) {
+        Ok(recovery_codes) => {
+            AuditEvent::success(AuditKind::TwoFactorEnabled)
+                .actor(user.id, &user.name)
+                .client(&client)
+                .record(&app.audit);
+            (StatusCode::OK, Json(RecoveryCodes { recovery_codes })).into_response()
+        }
+        Err(e) => (StatusCode::BAD_REQUEST, format!("Bad Request: {e}")).into_response(),
+    }
+}
+
+pub async fn handle_disable(
+    State(app): State<Arc<AppState>>,
+    Parent(mut user): Parent,
+    _: Session,
+    client: Client,
+    Json(req): Json<DisableRequest>,
+) -> impl IntoResponse {
+    info!("totp disable request: {}", user.id);
+    let (id, name) = (user.id, user.name.clone());
+    let result = {
+        let app = app.clone();
+        super::blocking(move || user.disable_totp(&app, &req.password, &req.code)).await
+    };
+    let event = AuditEvent::new(AuditKind::TwoFactorDisabled, outcome(&result))
+        .actor(id, &name)
+        .client(&client);
+    match result {
+        Ok(()) => {
+            event.record(&app.audit);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => {
+            event.detail(e.to_string()).record(&app.audit);
+            (StatusCode::BAD_REQUEST, format!("Bad Request: {e}")).into_response()
+        }
+    }
+}
+
+pub async fn handle_login_challenge(
+    State(app): State<Arc<AppState>>,
+    client: Client,
+    Json(req): Json<ChallengeRequest>,
+) -> impl IntoResponse {
+    let unauthorized = || (StatusCode::UNAUTHORIZED, "Unauthorized: invalid code").into_response();
+    let Ok((mut user, challenge)) = Auth::from_challenge_token(&app, &req.challenge_token) else {
+        AuditEvent::failure(AuditKind::SecondFactor)
+            .client(&client)
+            .detail("invalid challenge token")
+            .record(&app.audit);
+        return unauthorized();
+    };
+    let ip = client.addr.ip();
+    info!("login challenge request: {} from {}", user.name, client.addr);
+    let event = AuditEvent::failure(AuditKind::SecondFactor)
+        .actor(user.id, &user.name)
+        .client(&client);
+    if let Some(wait) = app.throttle.check(&user.name, ip) {
+        event.detail("locked out").record(&app.audit);
+        return super::login::too_many_attempts(wait);
+    }
+    match user.complete_login(&app, &challenge, &req.code) {
+        Ok(resp) => {
+            app.throttle.record_success(&user.name);
+            AuditEvent {
+                outcome: Outcome::Success,
+                ..event
+            }
+            .record(&app.audit);
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+        Err(e) => {
+            info!("login challenge failed for {}: {}", user.name, e);
+            app.throttle.record_failure(&user.name, ip);
+            event.detail(e.to_string()).record(&app.audit);
+            unauthorized()
+        }
+    }
+}
@@ -0,0 +1,268 @@
+use std::{collections::HashMap, fs, path::PathBuf, sync::Mutex};
+
+use anyhow::{anyhow, Context, Result};
+use blake2::{Blake2b512, Digest};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+
+use super::{repository::write_atomic, Auth, JWTData};
+use crate::state::AppState;
+
+/// Every key starts with this, so keys can be told apart from access tokens
+/// and found by secret scanners.
+pub const API_KEY_PREFIX: &str = "cwk_";
+/// Token families of key-authenticated requests are `key:<key id>`.
+const KEY_FAMILY_PREFIX: &str = "key:";
+/// `last_used` is only persisted when it moved by more than this.
+const LAST_USED_RESOLUTION: i64 = 60;
+
+/// What a personal API key may be used for. Access tokens may do everything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// `GET` requests on the REST API.
+    Read,
+    /// Every other REST request.
+    Write,
+    /// Connecting to the WebSocket.
+    Chat,
+}
+
+/// A stored key. Only a hash of the secret part is kept.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ApiKey {
+    pub id: String,
+    pub uid: u64,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub created: i64,
+    pub expires: Option<i64>,
+    pub last_used: Option<i64>,
+    hash: String,
+}
+
+pub trait ApiKeyStore: Send + Sync {
+    fn insert(&self, key: ApiKey) -> Result<()>;
+    fn find(&self, id: &str) -> Option<ApiKey>;
+    fn list(&self, uid: u64) -> Vec<ApiKey>;
+    /// Removes key `id` of `uid`, returns whether it existed.
+    fn remove(&self, uid: u64, id: &str) -> Result<bool>;
+    fn touch(&self, id: &str, now: i64) -> Result<()>;
+}
+
+pub struct FileApiKeyStore {
+    path: PathBuf,
+    keys: Mutex<HashMap<String, ApiKey>>,
+}
+
+impl FileApiKeyStore {
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let keys = if path.exists() {
+            let data = fs::read(&path).with_context(|| format!("read {}", path.display()))?;
+            serde_json::from_slice(&data).with_context(|| format!("parse {}", path.display()))?
+        } else {
+            HashMap::new()
+        };
+        Ok(Self {
+            path,
+            keys: Mutex::new(keys),
+        })
+    }
+
+    fn save(&self, keys: &HashMap<String, ApiKey>) -> Result<()> {
+        write_atomic(&self.path, &serde_json::to_vec(keys)?)
+    }
+}
+
+impl ApiKeyStore for FileApiKeyStore {
+    fn insert(&self, key: ApiKey) -> Result<()> {
+        let mut keys = self.keys.lock().unwrap();
+        keys.insert(key.id.clone(), key);
+        self.save(&keys)
+    }
+
+    fn find(&self, id: &str) -> Option<ApiKey> {
+        self.keys.lock().unwrap().get(id).cloned()
+    }
+
+    fn list(&self, uid: u64) -> Vec<ApiKey> {
+        let keys = self.keys.lock().unwrap();
+        let mut keys = keys
+            .values()
+            .filter(|k| k.uid == uid)
+            .cloned()
+            .collect::<Vec<_>>();
+        keys.sort_by_key(|k| k.created);
+        keys
+    }
+
+    fn remove(&self, uid: u64, id: &str) -> Result<bool> {
+        let mut keys = self.keys.lock().unwrap();
+        if keys.get(id).is_none_or(|k| k.uid != uid) {
+            return Ok(false);
+        }
+        keys.remove(id);
+        self.save(&keys)?;
+        Ok(true)
+    }
+
+    fn touch(&self, id: &str, now: i64) -> Result<()> {
+        let mut keys = self.keys.lock().unwrap();
+        let Some(key) = keys.get_mut(id) else {
+            return Ok(());
+        };
+        if key.last_used.is_some_and(|t| now - t < LAST_USED_RESOLUTION) {
+            return Ok(());
+        }
+        key.last_used = Some(now);
+        self.save(&keys)
+    }
+}
+
+/// Keys are `cwk_<id>_<secret>`. The secret is random, so a fast hash is
+/// enough to store it.
+fn hash_secret(secret: &str) -> String {
+    hex::encode(Blake2b512::digest(secret.as_bytes()))
+}
+
+fn split_key(key: &str) -> Option<(&str, &str)> {
+    key.strip_prefix(API_KEY_PREFIX)?.split_once('_')
+}
+
+pub fn is_api_key(credential: &str) -> bool {
+    credential.starts_with(API_KEY_PREFIX)
+}
+
+/// The key id behind claims produced by [`Auth::decode_api_key`].
+pub fn key_id(claims: &JWTData) -> Option<&str> {
+    claims.fid.strip_prefix(KEY_FAMILY_PREFIX)
+}
+
+pub fn is_key_revoked(app: &AppState, claims: &JWTData) -> bool {
+    key_id(claims).is_none_or(|id| app.api_keys.find(id).is_none())
+}
+
+impl JWTData {
+    /// Whether these claims may be used for `scope`. Claims of access tokens
+    /// carry no scopes and allow everything.
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.as_ref().is_none_or(|s| s.contains(&scope))
+    }
+}
+
+impl Auth {
+    /// Creates a key and returns it together with the full key string, which
+    /// is not stored and cannot be shown again.
+    pub fn create_api_key(
+        &self,
+        app: &AppState,
+        name: String,
+        scopes: Vec<Scope>,
+        expires: Option<i64>,
+    ) -> Result<(ApiKey, String)> {
+        let mut bytes = [0u8; 8];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let id = hex::encode(bytes);
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let secret = hex::encode(bytes);
+        let key = ApiKey {
+            id: id.clone(),
+            uid: self.id,
+            name,
+            scopes,
+            created: chrono::Utc::now().timestamp(),
+            expires,
+            last_used: None,
+            hash: hash_secret(&secret),
+        };
+        app.api_keys.insert(key.clone())?;
+        Ok((key, format!("{API_KEY_PREFIX}{id}_{secret}")))
+    }
+
+    pub fn api_keys(&self, app: &AppState) -> Vec<ApiKey> {
+        app.api_keys.list(self.id)
+    }
+
+    pub fn revoke_api_key(&self, app: &AppState, id: &str) -> Result<bool> {
+        app.api_keys.remove(self.id, id)
+    }
+
+    /// Checks an API key and returns claims standing in for an access token
+    /// of its owner, limited to the scopes of the key.
+    pub fn decode_api_key(app: &AppState, credential: &str) -> Result<JWTData> {
+        let invalid = || anyhow!("invalid api key");
+        let (id, secret) = split_key(credential).ok_or_else(invalid)?;
+        let key = app.api_keys.find(id).ok_or_else(invalid)?;
+        if !bool::from(hash_secret(secret).as_bytes().ct_eq(key.hash.as_bytes())) {
+            return Err(invalid());
+        }
+        let now = chrono::Utc::now().timestamp();
+        if key.expires.is_some_and(|exp| exp < now) {
+            return Err(anyhow!("api key expired"));
+        }
+        let user = Self::new_by_id(app, key.uid)?;
+        if let Err(e) = app.api_keys.touch(id, now) {
+            tracing::error!("update api key {} failed: {:?}", id, e);
+        }
+        Ok(JWTData {
+            role: user.effective_role(app),
+            name: user.name,
+            id: user.id,
+            exp: key.expires.unwrap_or(i64::MAX),
+            jti: key.id.clone(),
+            fid: format!("{KEY_FAMILY_PREFIX}{}", key.id),
+            scopes: Some(key.scopes),
+        })
+    }
+
+    /// Accepts either an access token or an API key.
+    pub fn authenticate(app: &AppState, credential: &str) -> Result<JWTData> {
+        if is_api_key(credential) {
+            Self::decode_api_key(app, credential)
+        } else {
+            Self::decode_access_token(app, credential)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_key() {
+        assert_eq!(split_key("cwk_0123abcd_s3cr3t"), Some(("0123abcd", "s3cr3t")));
+        assert_eq!(split_key("eyJ0eXAi.eyJuYW1l.sig"), None);
+        assert!(!is_api_key("eyJ0eXAi.eyJuYW1l.sig"));
+    }
+
+    #[test]
+    fn test_store_is_scoped_to_owner() {
+        let dir = std::env::temp_dir().join(format!("api-keys-{}", uuid::Uuid::new_v4()));
+        let store = FileApiKeyStore::open(dir.join("keys.json")).unwrap();
+        store
+            .insert(ApiKey {
+                id: "k1".into(),
+                uid: 1,
+                name: "cli".into(),
+                scopes: vec![Scope::Read],
+                created: 0,
+                expires: None,
+                last_used: None,
+                hash: hash_secret("secret"),
+            })
+            .unwrap();
+        assert!(!store.remove(2, "k1").unwrap());
+        assert_eq!(store.list(1).len(), 1);
+        assert!(store.list(2).is_empty());
+
+        let reopened = FileApiKeyStore::open(dir.join("keys.json")).unwrap();
+        assert_eq!(reopened.find("k1").unwrap().hash, hash_secret("secret"));
+        assert!(reopened.remove(1, "k1").unwrap());
+        assert!(reopened.find("k1").is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
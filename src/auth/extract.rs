@@ -0,0 +1,169 @@
+use std::sync::Arc;
+
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts},
+    http::{request::Parts, Method, StatusCode},
+    response::{IntoResponse, Response},
+};
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
+};
+
+use super::{api_key::Scope, Auth, JWTData, Role};
+use crate::state::AppState;
+
+/// The caller resolved from an `Authorization: Bearer <access token>` header,
+/// together with the claims of that token. An API key may stand in for the
+/// token if it has the `read` scope for `GET` requests or the `write` scope
+/// for anything else.
+pub struct Identity {
+    pub user: Auth,
+    pub claims: JWTData,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Identity
+where
+    Arc<AppState>: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let TypedHeader(Authorization(bearer)) =
+            TypedHeader::<Authorization<Bearer>>::from_request_parts(parts, state)
+                .await
+                .map_err(|_| unauthorized())?;
+        let app = Arc::<AppState>::from_ref(state);
+        let claims = Auth::authenticate(&app, bearer.token()).map_err(|_| unauthorized())?;
+        let scope = match parts.method {
+            Method::GET | Method::HEAD => Scope::Read,
+            _ => Scope::Write,
+        };
+        if !claims.allows(scope) {
+            return Err(forbidden());
+        }
+        let user = Auth::new_by_id(&app, claims.id).map_err(|_| unauthorized())?;
+        Ok(Self { user, claims })
+    }
+}
+
+/// Like [`Identity`], but only for access tokens. Used by routes that manage
+/// credentials, so an API key cannot mint or outlive itself.
+pub struct Session(pub Identity);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Session
+where
+    Arc<AppState>: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let identity = Identity::from_request_parts(parts, state).await?;
+        if identity.claims.scopes.is_some() {
+            return Err(forbidden());
+        }
+        Ok(Self(identity))
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Auth
+where
+    Arc<AppState>: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Identity::from_request_parts(parts, state).await?.user)
+    }
+}
+
+/// Extractors that resolve the caller like [`Auth`] and additionally reject
+/// it with `403` unless its current [effective role](Auth::effective_role)
+/// allows the given one. With `access_only` API keys are rejected as by
+/// [`Session`].
+macro_rules! role_extractor {
+    ($(#[$meta:meta])* $name:ident, $role:expr, access_only: $access_only:expr) => {
+        $(#[$meta])*
+        pub struct $name(pub Auth);
+
+        #[async_trait]
+        impl<S> FromRequestParts<S> for $name
+        where
+            Arc<AppState>: FromRef<S>,
+            S: Send + Sync,
+        {
+            type Rejection = Response;
+
+            async fn from_request_parts(
+                parts: &mut Parts,
+                state: &S,
+            ) -> Result<Self, Self::Rejection> {
+                let Identity { user, claims } = Identity::from_request_parts(parts, state).await?;
+                if $access_only && claims.scopes.is_some() {
+                    return Err(forbidden());
+                }
+                if !user.role.allows($role) {
+                    return Err(forbidden());
+                }
+                if !user.effective_role(&Arc::<AppState>::from_ref(state)).allows($role) {
+                    return Err((
+                        StatusCode::FORBIDDEN,
+                        "Forbidden: two-factor authentication required",
+                    )
+                        .into_response());
+                }
+                Ok(Self(user))
+            }
+        }
+    };
+}
+
+role_extractor!(
+    /// Only administrators, and only with an access token: an API key must
+    /// not be able to create users or change what keys may do.
+    Admin,
+    Role::Admin,
+    access_only: true
+);
+role_extractor!(
+    /// Parents and administrators, i.e. everyone but child profiles.
+    Parent,
+    Role::Parent,
+    access_only: false
+);
+
+/// A parent or administrator with an access token, whether or not it has the
+/// second factor its role needs. Only for setting up that factor.
+pub struct Enrolling(pub Auth);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Enrolling
+where
+    Arc<AppState>: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Session(Identity { user, .. }) = Session::from_request_parts(parts, state).await?;
+        if !user.role.allows(Role::Parent) {
+            return Err(forbidden());
+        }
+        Ok(Self(user))
+    }
+}
+
+fn unauthorized() -> Response {
+    (StatusCode::UNAUTHORIZED, "Unauthorized").into_response()
+}
+
+fn forbidden() -> Response {
+    (StatusCode::FORBIDDEN, "Forbidden").into_response()
+}
@@ -1,11 +1,24 @@
-#[allow(dead_code)]
-
 use anyhow::{anyhow, Result};
-use blake2::{Blake2b512, Digest};
-use jsonwebtoken::{decode, encode, Header, Validation};
+use blake2::{Blake2b512, Blake2s256, Digest};
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
+pub mod api_key;
+pub mod extract;
 pub mod jwt;
+pub mod oidc;
+pub mod password;
+pub mod repository;
+pub mod revocation;
+pub mod role;
+pub mod throttle;
+pub mod totp;
+
+use password::{hash_password, verify_decoy, verify_password, Verification};
+pub use role::Role;
+use totp::{TotpState, TwoFactorChallenge};
+
+use crate::state::AppState;
 
 const ACCESS_TOKEN_EXPIRE: i64 = 60 * 60;
 const REFRESH_TOKEN_EXPIRE: i64 = 60 * 60 * 24 * 7;
@@ -18,6 +31,13 @@ pub struct Auth {
     pub server_salt: String,
     #[serde(skip_serializing)]
     pub password: String,
+    #[serde(default)]
+    pub role: Role,
+    /// Owning parent of a child profile.
+    #[serde(default)]
+    pub parent_id: Option<u64>,
+    #[serde(skip_serializing, default)]
+    pub totp: Option<TotpState>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -32,11 +52,29 @@ pub struct JWTToken {
     pub refresh_token: String,
 }
 
+/// Result of the password step of a login.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum LoginResponse {
+    Tokens(JWTToken),
+    /// The user has TOTP enabled and must complete `/api/login/2fa`.
+    TwoFactor(TwoFactorChallenge),
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct JWTData {
     pub name: String,
     pub id: u64,
     pub exp: i64,
+    /// Unique id of this token.
+    pub jti: String,
+    /// Token family shared by every pair issued since the same login.
+    pub fid: String,
+    #[serde(default)]
+    pub role: Role,
+    /// Set for requests authenticated with an API key, see [`api_key::Scope`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scopes: Option<Vec<api_key::Scope>>,
 }
 
 pub fn add_salt(password: &str, salt: &str) -> Option<String> {
@@ -50,6 +88,64 @@ pub fn add_salt(password: &str, salt: &str) -> Option<String> {
     ))
 }
 
+fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.len() > 32 {
+        return Err(anyhow!("name must be 1 to 32 characters"));
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == '_' || c == '.' || c == '-')
+    {
+        return Err(anyhow!("name contains invalid characters"));
+    }
+    Ok(())
+}
+
+/// Client salts are UUIDs so that [`add_salt`] can split them into five parts.
+/// They are stored in canonical form so they look exactly like
+/// [`decoy_salt`]s.
+fn normalize_client_salt(salt: &str) -> Result<String> {
+    Uuid::parse_str(salt)
+        .map(|salt| salt.to_string())
+        .map_err(|_| anyhow!("client salt must be a uuid"))
+}
+
+/// Salt reported for a name without an account. It is derived from a server
+/// secret, so it is stable across requests and formatted like a real client
+/// salt, which keeps `/api/:user/salt` from revealing which names exist.
+pub fn decoy_salt(secret: &str, name: &str) -> String {
+    derived_salt(&["decoy-salt", secret, name])
+}
+
+/// Client salt of the seeded user. `CLIENT_PASSWORD_SALT` was never required
+/// to be a UUID, other values are replaced by a UUID derived from them.
+pub(crate) fn seed_client_salt(salt: &str) -> String {
+    normalize_client_salt(salt).unwrap_or_else(|_| derived_salt(&["seed-salt", salt]))
+}
+
+/// A salt formatted like a client salt, derived from `parts`.
+fn derived_salt(parts: &[&str]) -> String {
+    let mut hasher = Blake2s256::new();
+    for part in parts {
+        hasher.update(part.as_bytes());
+        hasher.update(b"\0");
+    }
+    let digest = hasher.finalize();
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&digest[..16]);
+    uuid::Builder::from_random_bytes(bytes)
+        .into_uuid()
+        .to_string()
+}
+
+/// Client salt for `name`, real or decoy.
+pub fn client_salt(app: &AppState, name: &str) -> String {
+    match Auth::new_by_name(app, name.to_owned()) {
+        Ok(user) => user.client_salt,
+        Err(_) => decoy_salt(app.config.get().auth.decoy_salt_secret(), name),
+    }
+}
+
 pub fn hash(msg: &str) -> String {
     let mut hasher = Blake2b512::new();
     hasher.update(msg.as_bytes());
@@ -71,93 +167,260 @@ impl Auth {
             password,
             client_salt,
             server_salt,
+            role: Role::Parent,
+            parent_id: None,
+            totp: None,
+        }
+    }
+
+    /// Creates and stores a new user. `password` is the client-side digest
+    /// computed with `client_salt`, the same value later sent to `/api/login`.
+    pub fn create(
+        app: &AppState,
+        name: String,
+        password: &str,
+        client_salt: String,
+        role: Role,
+        parent_id: Option<u64>,
+    ) -> Result<Self> {
+        validate_name(&name)?;
+        let client_salt = normalize_client_salt(&client_salt)?;
+        let mut user = Self::new(
+            name,
+            0,
+            String::new(),
+            client_salt,
+            Uuid::new_v4().to_string(),
+        );
+        user.role = role;
+        user.parent_id = parent_id;
+        user.set_password(password)?;
+        app.users.insert(user)
+    }
+
+    pub fn set_password(&mut self, password: &str) -> Result<()> {
+        if password.is_empty() {
+            return Err(anyhow!("password must not be empty"));
         }
+        let password =
+            add_salt(password, &self.server_salt).ok_or_else(|| anyhow!("invalid server salt"))?;
+        self.password = hash_password(&password)?;
+        Ok(())
     }
 
-    pub fn new_by_name(name: String) -> Result<Self> {
-        let db_name = std::env::var("CLIENT_NAME").expect("CLIENT_NAME not found");
-        if name != db_name {
-            return Err(anyhow!("user not found"));
+    /// Replaces the password after checking the current one. When
+    /// `client_salt` is given the new password must have been derived from it.
+    pub fn change_password(
+        &mut self,
+        app: &AppState,
+        old_password: &str,
+        new_password: &str,
+        client_salt: Option<String>,
+    ) -> Result<()> {
+        if !self.check(&self.name, old_password) {
+            return Err(anyhow!("invalid password"));
         }
+        let mut next = self.clone();
+        if let Some(client_salt) = client_salt {
+            next.client_salt = normalize_client_salt(&client_salt)?;
+        }
+        next.set_password(new_password)?;
+        *self = app.users.update_with(self.id, &mut |user| {
+            user.client_salt = next.client_salt.clone();
+            user.password = next.password.clone();
+            Ok(())
+        })?;
+        Ok(())
+    }
 
-        let db_id = std::env::var("CLIENT_ID").expect("CLIENT_ID not found");
-        let db_password = std::env::var("CLIENT_PASSWORD").expect("CLIENT_PASSWORD not found");
-        let db_client_salt = std::env::var("CLIENT_PASSWORD_SALT").expect("CLIENT_PASSWORD_SALT not found");
-        let db_server_salt = std::env::var("SERVER_PASSWORD_SALT").expect("SERVER_PASSWORD_SALT not found");
-        Ok(Self::new(
-            name,
-            db_id.parse::<u64>().unwrap(),
-            db_password,
-            db_client_salt,
-            db_server_salt,
-        ))
-    }
-
-    pub fn from_access_token(token: &str) -> Result<Self> {
-        let token_data = decode::<JWTData>(token, &jwt::KEYS.decoding, &Validation::default())
-            .map_err(|e| {
-                tracing::error!("validate_token error: {:?}", e);
-                anyhow!(e)
-            })?;
-        if token_data.claims.exp < chrono::Utc::now().timestamp() {
+    pub fn new_by_name(app: &AppState, name: String) -> Result<Self> {
+        app.users
+            .find_by_name(&name)?
+            .ok_or_else(|| anyhow!("user not found"))
+    }
+
+    pub fn new_by_id(app: &AppState, id: u64) -> Result<Self> {
+        app.users
+            .find_by_id(id)?
+            .ok_or_else(|| anyhow!("user not found"))
+    }
+
+    pub fn list(app: &AppState) -> Result<Vec<Self>> {
+        app.users.list()
+    }
+
+    /// Child profiles owned by this user.
+    pub fn children(&self, app: &AppState) -> Result<Vec<Self>> {
+        Ok(Self::list(app)?
+            .into_iter()
+            .filter(|u| u.parent_id == Some(self.id))
+            .collect())
+    }
+
+    /// Decodes an access token and rejects it if it has expired or its token
+    /// family was revoked.
+    pub fn decode_access_token(app: &AppState, token: &str) -> Result<JWTData> {
+        let claims = app.keys.access.decode::<JWTData>(token).map_err(|e| {
+            tracing::error!("validate_token error: {:?}", e);
+            e
+        })?;
+        if claims.exp < chrono::Utc::now().timestamp() {
             return Err(anyhow!("access token expired"));
         };
-        Self::new_by_name(token_data.claims.name)
-    }
-
-    pub fn from_refresh_token(token: &str) -> Result<Self> {
-        let token_data =
-            decode::<JWTData>(token, &jwt::KEYS.refresh_decoding, &Validation::default()).map_err(
-                |e| {
-                    tracing::error!("validate_token error: {:?}", e);
-                    anyhow!(e)
-                },
-            )?;
-        if token_data.claims.exp < chrono::Utc::now().timestamp() {
+        if app.revocations.is_revoked(&claims.fid) {
+            return Err(anyhow!("access token revoked"));
+        }
+        Ok(claims)
+    }
+
+    pub fn is_revoked(app: &AppState, claims: &JWTData) -> bool {
+        if claims.scopes.is_some() {
+            return api_key::is_key_revoked(app, claims);
+        }
+        app.revocations.is_revoked(&claims.fid)
+    }
+
+    pub fn from_access_token(app: &AppState, token: &str) -> Result<Self> {
+        let claims = Self::decode_access_token(app, token)?;
+        Self::new_by_id(app, claims.id)
+    }
+
+    pub fn decode_refresh_token(app: &AppState, token: &str) -> Result<JWTData> {
+        let claims = app.keys.refresh.decode::<JWTData>(token).map_err(|e| {
+            tracing::error!("validate_token error: {:?}", e);
+            e
+        })?;
+        if claims.exp < chrono::Utc::now().timestamp() {
             return Err(anyhow!("refresh token expired"));
         }
-        Self::new_by_name(token_data.claims.name)
+        Ok(claims)
     }
 
-    pub fn refresh_access_token(&self) -> Result<JWTToken> {
-        JWTToken::generate_token::<JWTData>(&self.to_login_response())
+    pub fn from_refresh_token(app: &AppState, token: &str) -> Result<(Self, JWTData)> {
+        let claims = Self::decode_refresh_token(app, token)?;
+        Ok((Self::new_by_id(app, claims.id)?, claims))
     }
 
-    pub fn generate_token(&self) -> Result<JWTToken> {
-        JWTToken::generate_token::<JWTData>(&self.to_login_response())
+    /// Issues a new token pair in the family of `claims`, consuming the
+    /// refresh token they came from.
+    pub fn refresh_access_token(&self, app: &AppState, claims: &JWTData) -> Result<JWTToken> {
+        let tokens = self.to_login_response(&claims.fid, self.effective_role(app));
+        app.revocations
+            .rotate(&claims.fid, &claims.jti, &tokens.1.jti, tokens.1.exp)?;
+        JWTToken::generate_token::<JWTData>(&app.keys, &tokens)
+    }
+
+    /// Issues a token pair that starts a new token family.
+    pub fn generate_token(&self, app: &AppState) -> Result<JWTToken> {
+        let fid = Uuid::new_v4().to_string();
+        let tokens = self.to_login_response(&fid, self.effective_role(app));
+        app.revocations
+            .start_family(&fid, self.id, &tokens.1.jti, tokens.1.exp)?;
+        JWTToken::generate_token::<JWTData>(&app.keys, &tokens)
+    }
+
+    /// Revokes the token family of `claims`, or every family of the user when
+    /// `all` is set.
+    pub fn logout(app: &AppState, claims: &JWTData, all: bool) -> Result<()> {
+        if all {
+            app.revocations.revoke_user(claims.id)?;
+        } else {
+            app.revocations.revoke_family(&claims.fid)?;
+        }
+        Ok(())
     }
 
-    pub fn to_login_response(&self) -> (JWTData, JWTData) {
+    pub fn to_login_response(&self, fid: &str, role: Role) -> (JWTData, JWTData) {
+        let now = chrono::Utc::now().timestamp();
         (
             JWTData {
                 name: self.name.clone(),
                 id: self.id,
-                exp: chrono::Utc::now().timestamp() + ACCESS_TOKEN_EXPIRE,
+                exp: now + ACCESS_TOKEN_EXPIRE,
+                jti: Uuid::new_v4().to_string(),
+                fid: fid.to_owned(),
+                role,
+                scopes: None,
             },
             JWTData {
                 name: self.name.clone(),
                 id: self.id,
-                exp: chrono::Utc::now().timestamp() + REFRESH_TOKEN_EXPIRE,
+                exp: now + REFRESH_TOKEN_EXPIRE,
+                jti: Uuid::new_v4().to_string(),
+                fid: fid.to_owned(),
+                role,
+                scopes: None,
             },
         )
     }
 
+    pub fn verify(&self, name: &str, password: &str) -> Verification {
+        if self.name != name {
+            return Verification::Invalid;
+        }
+        match add_salt(password, &self.server_salt) {
+            Some(password) => verify_password(&password, &self.password),
+            None => Verification::Invalid,
+        }
+    }
+
     pub fn check(&self, name: &str, password: &str) -> bool {
-        let salt = std::env::var("SERVER_PASSWORD_SALT").expect("SERVER_PASSWORD_SALT not found");
-        let db_name = &self.name;
-        let db_password = &self.password;
-        let password = add_salt(password, &salt);
-        if let Some(password) = password {
-            return db_name == name && db_password == &hash(&password);
+        self.verify(name, password) != Verification::Invalid
+    }
+
+    /// Looks `name` up and logs it in. An unknown name costs the same
+    /// password check as a known one, so timing doesn't reveal accounts.
+    pub fn login_by_name(
+        app: &AppState,
+        name: &str,
+        password: &str,
+    ) -> Result<(u64, LoginResponse)> {
+        let user = match Self::new_by_name(app, name.to_owned()) {
+            Ok(user) => user,
+            Err(e) => {
+                verify_decoy(password);
+                return Err(e);
+            }
+        };
+        Ok((user.id, user.login(app, name, password)?))
+    }
+
+    pub fn login(&self, app: &AppState, name: &str, password: &str) -> Result<LoginResponse> {
+        match self.verify(name, password) {
+            Verification::Invalid => return Err(anyhow!("login failed")),
+            Verification::Legacy => self.upgrade_password(app, password),
+            Verification::Valid => {}
         }
-        false
+        self.login_response(app)
     }
 
-    pub fn login(&self, name: &str, password: &str) -> Result<JWTToken> {
-        if !self.check(name, password) {
-            return Err(anyhow!("login failed"));
+    /// What a login answers once the first factor passed: the token pair,
+    /// or a challenge when TOTP is enabled.
+    pub fn login_response(&self, app: &AppState) -> Result<LoginResponse> {
+        if self.totp_enabled() {
+            return Ok(LoginResponse::TwoFactor(self.two_factor_challenge(app)?));
         }
-        JWTToken::generate_token::<JWTData>(&self.to_login_response())
+        Ok(LoginResponse::Tokens(self.generate_token(app)?))
+    }
+
+    /// Rehashes a legacy Blake2b credential with Argon2id. Failures are only
+    /// logged, the next login will try again.
+    fn upgrade_password(&self, app: &AppState, password: &str) {
+        let mut next = self.clone();
+        if let Err(e) = next.set_password(password).and_then(|_| {
+            app.users.update_with(self.id, &mut |user| {
+                // Another request may have changed the password meanwhile.
+                if user.password != self.password {
+                    return Err(anyhow!("password changed concurrently"));
+                }
+                user.password = next.password.clone();
+                Ok(())
+            })
+        }) {
+            tracing::error!("upgrade password hash for user {} error: {:?}", self.id, e);
+            return;
+        }
+        tracing::info!("upgraded password hash for user {}", self.id);
     }
 }
 
@@ -169,20 +432,39 @@ impl JWTToken {
         }
     }
 
-    pub fn generate_token<T>((access_claims, refresh_claims): &(T, T)) -> Result<Self>
+    pub fn generate_token<T>(
+        keys: &jwt::Keys,
+        (access_claims, refresh_claims): &(T, T),
+    ) -> Result<Self>
     where
         T: Serialize + Sized,
     {
-        let token =
-            encode(&Header::default(), &access_claims, &jwt::KEYS.encoding).map_err(|e| {
-                tracing::error!("generate_token error: {:?}", e);
-                anyhow!(e)
-            })?;
-        let refresh_token = encode(
-            &Header::default(),
-            refresh_claims,
-            &jwt::KEYS.refresh_encoding,
-        )?;
+        let token = keys.access.encode(access_claims)?;
+        let refresh_token = keys.refresh.encode(refresh_claims)?;
         Ok(Self::new(token, refresh_token))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decoy_salt_is_stable_uuid() {
+        let salt = decoy_salt("secret", "nobody");
+        assert_eq!(salt, decoy_salt("secret", "nobody"));
+        assert_ne!(salt, decoy_salt("secret", "somebody"));
+        assert_ne!(salt, decoy_salt("other", "nobody"));
+        assert_eq!(normalize_client_salt(&salt).unwrap(), salt);
+        assert!(add_salt("pw", &salt).is_some());
+    }
+
+    #[test]
+    fn test_seed_client_salt_is_a_uuid() {
+        let salt = "1B4E28BA-2FA1-11D2-883F-0016D3CCA427";
+        assert_eq!(seed_client_salt(salt), salt.to_lowercase());
+        let derived = seed_client_salt("abcdefgh12345678");
+        assert_eq!(derived, seed_client_salt("abcdefgh12345678"));
+        assert_eq!(normalize_client_salt(&derived).unwrap(), derived);
+    }
+}
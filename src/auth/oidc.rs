@@ -0,0 +1,444 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context, Result};
+use data_encoding::BASE64URL_NOPAD;
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{Jwk, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use rand::RngCore;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+use super::{repository::write_atomic, Auth, Role};
+use crate::{config, state::AppState};
+
+/// How long a started login may take before its state is forgotten.
+const PENDING_LOGIN_TTL: Duration = Duration::from_secs(10 * 60);
+/// Started logins kept at most, the oldest is dropped for a new one.
+const MAX_PENDING_LOGINS: usize = 1024;
+/// Longest name [`Auth::create`] accepts, in bytes.
+const MAX_NAME_LEN: usize = 32;
+/// Refetching the JWKS for an unknown `kid` is rate limited to this.
+const JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Identity provider settings, resolved from the validated `[oidc]` section of
+/// the [`Config`](crate::config::Config).
+pub struct OidcConfig {
+    pub issuer: String,
+    /// Defaults to `<issuer>/.well-known/openid-configuration`.
+    pub discovery_url: String,
+    /// Overrides the `jwks_uri` of the discovery document.
+    pub jwks_url: Option<String>,
+    pub client_id: String,
+    /// Only for confidential clients, public clients rely on PKCE alone.
+    pub client_secret: Option<String>,
+    /// The callback route of this server as registered at the provider.
+    pub redirect_uri: String,
+    pub scopes: String,
+    /// Creates a local user for unknown subjects instead of rejecting them.
+    pub auto_create: bool,
+    pub default_role: Role,
+    /// Where the browser is sent after login, with the tokens in the
+    /// fragment. Without it the callback answers with the tokens as JSON.
+    pub post_login_redirect: Option<String>,
+}
+
+impl OidcConfig {
+    /// Resolves the `[oidc]` settings, `None` when OIDC is disabled.
+    pub fn new(settings: &config::OidcConfig) -> Option<Self> {
+        let issuer = settings.issuer.as_deref()?.trim_end_matches('/').to_owned();
+        Some(Self {
+            discovery_url: settings
+                .discovery_url
+                .clone()
+                .unwrap_or_else(|| format!("{issuer}/.well-known/openid-configuration")),
+            jwks_url: settings.jwks_url.clone(),
+            client_id: settings.client_id.clone()?,
+            client_secret: settings.client_secret.clone(),
+            redirect_uri: settings.redirect_uri.clone()?,
+            scopes: settings.scopes.clone(),
+            auto_create: settings.auto_create,
+            default_role: settings.default_role,
+            post_login_redirect: settings.post_login_redirect.clone(),
+            issuer,
+        })
+    }
+}
+
+/// The parts of the discovery document this server uses.
+#[derive(Debug, Clone, Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// Claims of an ID token. Only `sub` identifies the user, the name claims
+/// just seed the name of auto-created users.
+#[derive(Debug, Deserialize)]
+pub struct IdClaims {
+    pub sub: String,
+    pub nonce: Option<String>,
+    pub preferred_username: Option<String>,
+    pub email: Option<String>,
+}
+
+struct PendingLogin {
+    verifier: String,
+    nonce: String,
+    started: Instant,
+}
+
+struct CachedJwks {
+    keys: JwkSet,
+    fetched: Option<Instant>,
+}
+
+/// Authorization-code login with PKCE against one OpenID provider.
+pub struct Oidc {
+    pub config: OidcConfig,
+    http: reqwest::Client,
+    discovery: RwLock<Option<Discovery>>,
+    jwks: RwLock<CachedJwks>,
+    pending: Mutex<HashMap<String, PendingLogin>>,
+}
+
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    BASE64URL_NOPAD.encode(&bytes)
+}
+
+/// Name for a user created from `claims`: `preferred_username`, else `email`.
+/// Addresses are cut to their local part and characters local names can't
+/// have become `_`.
+fn local_name(claims: &IdClaims) -> Option<String> {
+    let name = claims
+        .preferred_username
+        .as_deref()
+        .or(claims.email.as_deref())?;
+    let name = name.split('@').next().unwrap_or_default();
+    let mut local = String::new();
+    for c in name.chars() {
+        let c = if c.is_alphanumeric() || matches!(c, '_' | '.' | '-') {
+            c
+        } else {
+            '_'
+        };
+        if local.len() + c.len_utf8() > MAX_NAME_LEN {
+            break;
+        }
+        local.push(c);
+    }
+    (!local.is_empty()).then_some(local)
+}
+
+/// The S256 code challenge of `verifier`, RFC 7636 section 4.2.
+fn code_challenge(verifier: &str) -> String {
+    BASE64URL_NOPAD.encode(&Sha256::digest(verifier.as_bytes()))
+}
+
+impl Oidc {
+    pub fn new(config: OidcConfig) -> Self {
+        Self {
+            config,
+            http: reqwest::Client::new(),
+            discovery: RwLock::new(None),
+            jwks: RwLock::new(CachedJwks {
+                keys: JwkSet { keys: Vec::new() },
+                fetched: None,
+            }),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The discovery document, fetched once and then cached.
+    async fn discovery(&self) -> Result<Discovery> {
+        if let Some(discovery) = self.discovery.read().await.as_ref() {
+            return Ok(discovery.clone());
+        }
+        let discovery = self
+            .http
+            .get(&self.config.discovery_url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Discovery>()
+            .await
+            .context("fetch oidc discovery document")?;
+        if discovery.issuer.trim_end_matches('/') != self.config.issuer {
+            return Err(anyhow!("discovery issuer {} does not match", discovery.issuer));
+        }
+        *self.discovery.write().await = Some(discovery.clone());
+        Ok(discovery)
+    }
+
+    /// Starts a login and returns the provider URL to send the browser to,
+    /// together with the `state` the callback has to present.
+    pub async fn authorization_url(&self) -> Result<(String, String)> {
+        let discovery = self.discovery().await?;
+        let state = random_token();
+        let nonce = random_token();
+        let verifier = random_token();
+        let url = Url::parse_with_params(
+            &discovery.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", &self.config.client_id),
+                ("redirect_uri", &self.config.redirect_uri),
+                ("scope", &self.config.scopes),
+                ("state", &state),
+                ("nonce", &nonce),
+                ("code_challenge", &code_challenge(&verifier)),
+                ("code_challenge_method", "S256"),
+            ],
+        )?;
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, p| p.started.elapsed() < PENDING_LOGIN_TTL);
+        if pending.len() >= MAX_PENDING_LOGINS {
+            let oldest = pending
+                .iter()
+                .min_by_key(|(_, p)| p.started)
+                .map(|(state, _)| state.clone());
+            if let Some(oldest) = oldest {
+                pending.remove(&oldest);
+            }
+        }
+        pending.insert(
+            state.clone(),
+            PendingLogin {
+                verifier,
+                nonce,
+                started: Instant::now(),
+            },
+        );
+        Ok((url.into(), state))
+    }
+
+    /// Redeems the code of the callback for `state` and returns the verified
+    /// claims of the ID token.
+    pub async fn exchange(&self, code: &str, state: &str) -> Result<IdClaims> {
+        let pending = self
+            .pending
+            .lock()
+            .unwrap()
+            .remove(state)
+            .filter(|p| p.started.elapsed() < PENDING_LOGIN_TTL)
+            .ok_or_else(|| anyhow!("unknown or expired login state"))?;
+        let discovery = self.discovery().await?;
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.config.redirect_uri),
+            ("client_id", &self.config.client_id),
+            ("code_verifier", &pending.verifier),
+        ];
+        if let Some(secret) = &self.config.client_secret {
+            form.push(("client_secret", secret));
+        }
+        let tokens = self
+            .http
+            .post(&discovery.token_endpoint)
+            .form(&form)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<TokenResponse>()
+            .await
+            .context("redeem authorization code")?;
+        let claims = self.verify_id_token(&tokens.id_token, &discovery).await?;
+        if claims.nonce.as_deref() != Some(pending.nonce.as_str()) {
+            return Err(anyhow!("id token nonce does not match"));
+        }
+        Ok(claims)
+    }
+
+    async fn verify_id_token(&self, token: &str, discovery: &Discovery) -> Result<IdClaims> {
+        let header = decode_header(token)?;
+        if matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+            return Err(anyhow!("id token signed with {:?}", header.alg));
+        }
+        let jwk = match self.find_key(header.kid.as_deref()).await {
+            Some(jwk) => jwk,
+            None => {
+                self.refresh_jwks(discovery).await?;
+                self.find_key(header.kid.as_deref())
+                    .await
+                    .ok_or_else(|| anyhow!("no provider key for kid {:?}", header.kid))?
+            }
+        };
+        let mut validation = Validation::new(header.alg);
+        validation.set_audience(&[&self.config.client_id]);
+        validation.set_issuer(&[&discovery.issuer]);
+        let data = decode::<IdClaims>(token, &DecodingKey::from_jwk(&jwk)?, &validation)?;
+        Ok(data.claims)
+    }
+
+    async fn find_key(&self, kid: Option<&str>) -> Option<Jwk> {
+        let jwks = self.jwks.read().await;
+        match kid {
+            Some(kid) => jwks.keys.find(kid).cloned(),
+            None if jwks.keys.keys.len() == 1 => jwks.keys.keys.first().cloned(),
+            None => None,
+        }
+    }
+
+    async fn refresh_jwks(&self, discovery: &Discovery) -> Result<()> {
+        let mut jwks = self.jwks.write().await;
+        if jwks.fetched.is_some_and(|t| t.elapsed() < JWKS_REFRESH_INTERVAL) {
+            return Ok(());
+        }
+        let url = self.config.jwks_url.as_ref().unwrap_or(&discovery.jwks_uri);
+        jwks.keys = self
+            .http
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<JwkSet>()
+            .await
+            .context("fetch oidc jwks")?;
+        jwks.fetched = Some(Instant::now());
+        Ok(())
+    }
+
+    /// The local user linked to the subject of `claims`. Unknown subjects
+    /// get a new user when `oidc.auto_create` is set.
+    pub fn local_user(&self, app: &AppState, claims: &IdClaims) -> Result<Auth> {
+        if let Some(uid) = app.oidc_links.find(&self.config.issuer, &claims.sub) {
+            return Auth::new_by_id(app, uid);
+        }
+        if !self.config.auto_create {
+            return Err(anyhow!("subject {} is not linked to a user", claims.sub));
+        }
+        let name = local_name(claims).ok_or_else(|| anyhow!("id token has no usable name"))?;
+        if Auth::new_by_name(app, name.clone()).is_ok() {
+            // Taking over an existing account needs an administrator's link.
+            return Err(anyhow!("user {} already exists", name));
+        }
+        // Nobody knows the password, the user can only sign in through OIDC.
+        let user = Auth::create(
+            app,
+            name,
+            &random_token(),
+            Uuid::new_v4().to_string(),
+            self.config.default_role,
+            None,
+        )?;
+        app.oidc_links.link(&self.config.issuer, &claims.sub, user.id)?;
+        Ok(user)
+    }
+}
+
+/// A provider subject linked to a local user.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OidcLink {
+    pub issuer: String,
+    pub subject: String,
+    pub uid: u64,
+}
+
+/// Links between provider subjects and local users.
+pub trait OidcLinkStore: Send + Sync {
+    fn find(&self, issuer: &str, subject: &str) -> Option<u64>;
+    /// Links `subject` to `uid`, replacing an earlier link of the subject.
+    fn link(&self, issuer: &str, subject: &str, uid: u64) -> Result<()>;
+    fn list(&self) -> Vec<OidcLink>;
+}
+
+pub struct FileOidcLinkStore {
+    path: PathBuf,
+    links: Mutex<Vec<OidcLink>>,
+}
+
+impl FileOidcLinkStore {
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let links = if path.exists() {
+            let data = fs::read(&path).with_context(|| format!("read {}", path.display()))?;
+            serde_json::from_slice(&data).with_context(|| format!("parse {}", path.display()))?
+        } else {
+            Vec::new()
+        };
+        Ok(Self {
+            path,
+            links: Mutex::new(links),
+        })
+    }
+}
+
+impl OidcLinkStore for FileOidcLinkStore {
+    fn find(&self, issuer: &str, subject: &str) -> Option<u64> {
+        self.links
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|l| l.issuer == issuer && l.subject == subject)
+            .map(|l| l.uid)
+    }
+
+    fn link(&self, issuer: &str, subject: &str, uid: u64) -> Result<()> {
+        let mut links = self.links.lock().unwrap();
+        links.retain(|l| !(l.issuer == issuer && l.subject == subject));
+        links.push(OidcLink {
+            issuer: issuer.to_owned(),
+            subject: subject.to_owned(),
+            uid,
+        });
+        write_atomic(&self.path, &serde_json::to_vec(&*links)?)
+    }
+
+    fn list(&self) -> Vec<OidcLink> {
+        self.links.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_code_challenge() {
+        // Example from RFC 7636 appendix B.
+        assert_eq!(
+            code_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[test]
+    fn test_local_name() {
+        let name = |preferred_username: Option<&str>, email: Option<&str>| {
+            local_name(&IdClaims {
+                sub: "s".into(),
+                nonce: None,
+                preferred_username: preferred_username.map(str::to_owned),
+                email: email.map(str::to_owned),
+            })
+        };
+        assert_eq!(name(Some("ann"), Some("bob@x.org")).as_deref(), Some("ann"));
+        assert_eq!(name(Some("ann@x.org"), None).as_deref(), Some("ann"));
+        assert_eq!(name(None, Some("a.b+tag@x.org")).as_deref(), Some("a.b_tag"));
+        assert_eq!(name(Some("Ann Lee/../x"), None).as_deref(), Some("Ann_Lee_.._x"));
+        assert_eq!(name(Some(&"é".repeat(40)), None).unwrap().len(), MAX_NAME_LEN);
+        assert_eq!(name(Some("@x.org"), None), None);
+        assert_eq!(name(None, None), None);
+    }
+}
@@ -0,0 +1,85 @@
+use anyhow::{anyhow, Result};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use subtle::ConstantTimeEq;
+
+use super::hash;
+
+/// Outcome of checking a password against a stored credential.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    Valid,
+    /// Valid, but stored in the legacy Blake2b format and should be rehashed.
+    Legacy,
+    Invalid,
+}
+
+/// Argon2id hash with the default parameters of a password nobody knows.
+/// Checked for unknown names so they take as long as a wrong password.
+const DECOY_HASH: &str = concat!(
+    "$argon2id$v=19$m=19456,t=2,p=1$cDuYIS4gTGCwQXFpERRNtQ",
+    "$weeU1Dgw/dQ+KN731NvVYT3ScWeq93Zw8NIVMIvBbXM",
+);
+
+/// Hashes an already salted password into an Argon2id PHC string.
+pub fn hash_password(salted: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(salted.as_bytes(), &salt)
+        .map(|h| h.to_string())
+        .map_err(|e| anyhow!("hash password error: {}", e))
+}
+
+/// Checks `salted` against `stored`, which is either a PHC string or a legacy
+/// hex encoded Blake2b512 digest. Both paths compare in constant time.
+pub fn verify_password(salted: &str, stored: &str) -> Verification {
+    if stored.starts_with('$') {
+        let Ok(parsed) = PasswordHash::new(stored) else {
+            tracing::error!("stored password hash is not a valid PHC string");
+            return Verification::Invalid;
+        };
+        return match Argon2::default().verify_password(salted.as_bytes(), &parsed) {
+            Ok(()) => Verification::Valid,
+            Err(_) => Verification::Invalid,
+        };
+    }
+    if bool::from(hash(salted).as_bytes().ct_eq(stored.as_bytes())) {
+        Verification::Legacy
+    } else {
+        Verification::Invalid
+    }
+}
+
+/// Spends the time of checking `password` against a real credential.
+pub fn verify_decoy(password: &str) {
+    let _ = verify_password(password, DECOY_HASH);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_password() {
+        let phc = hash_password("a-b-c-d-pw-e").unwrap();
+        assert!(phc.starts_with("$argon2id$"));
+        assert_eq!(verify_password("a-b-c-d-pw-e", &phc), Verification::Valid);
+        assert_eq!(verify_password("a-b-c-d-px-e", &phc), Verification::Invalid);
+
+        let legacy = hash("a-b-c-d-pw-e");
+        assert_eq!(verify_password("a-b-c-d-pw-e", &legacy), Verification::Legacy);
+        assert_eq!(verify_password("a-b-c-d-px-e", &legacy), Verification::Invalid);
+    }
+
+    #[test]
+    fn test_decoy_costs_like_a_real_hash() {
+        let decoy = PasswordHash::new(DECOY_HASH).unwrap();
+        let real = hash_password("a-b-c-d-pw-e").unwrap();
+        let real = PasswordHash::new(&real).unwrap();
+        assert_eq!(decoy.algorithm, real.algorithm);
+        assert_eq!(decoy.params, real.params);
+        assert_eq!(verify_password("a-b-c-d-pw-e", DECOY_HASH), Verification::Invalid);
+    }
+}
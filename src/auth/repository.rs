@@ -0,0 +1,255 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use super::{seed_client_salt, totp::TotpState, Auth, Role};
+use crate::config::{Config, SeedUserConfig};
+
+/// Storage backend for user accounts.
+pub trait UserRepository: Send + Sync {
+    fn find_by_name(&self, name: &str) -> Result<Option<Auth>>;
+    fn find_by_id(&self, id: u64) -> Result<Option<Auth>>;
+    fn list(&self) -> Result<Vec<Auth>>;
+    /// Stores a new user. An `id` of `0` is replaced by the next free id.
+    fn insert(&self, user: Auth) -> Result<Auth>;
+    /// Applies `f` to the stored user `id` and returns the result. Runs under
+    /// the store's lock, so the change can't be lost to a concurrent writer.
+    /// Nothing is written when `f` fails.
+    fn update_with(&self, id: u64, f: &mut dyn FnMut(&mut Auth) -> Result<()>) -> Result<Auth>;
+}
+
+/// On-disk representation of a user. Unlike [`Auth`] it keeps the password
+/// hash when serialized.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct UserRecord {
+    name: String,
+    id: u64,
+    client_salt: String,
+    server_salt: String,
+    password: String,
+    /// Only read, records written before roles existed mark administrators
+    /// with it.
+    #[serde(default, skip_serializing)]
+    admin: bool,
+    #[serde(default)]
+    role: Option<Role>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    parent_id: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    totp: Option<TotpState>,
+}
+
+impl From<UserRecord> for Auth {
+    fn from(r: UserRecord) -> Self {
+        let mut user = Self::new(r.name, r.id, r.password, r.client_salt, r.server_salt);
+        user.role = r.role.unwrap_or(if r.admin { Role::Admin } else { Role::Parent });
+        user.parent_id = r.parent_id;
+        user.totp = r.totp;
+        user
+    }
+}
+
+impl From<&Auth> for UserRecord {
+    fn from(a: &Auth) -> Self {
+        Self {
+            name: a.name.clone(),
+            id: a.id,
+            client_salt: a.client_salt.clone(),
+            server_salt: a.server_salt.clone(),
+            password: a.password.clone(),
+            admin: false,
+            role: Some(a.role),
+            parent_id: a.parent_id,
+            totp: a.totp.clone(),
+        }
+    }
+}
+
+/// Keeps every user in a single JSON file which is rewritten on each change.
+pub struct FileUserRepository {
+    path: PathBuf,
+    users: Mutex<Vec<UserRecord>>,
+}
+
+impl FileUserRepository {
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let users = if path.exists() {
+            let data = fs::read(&path).with_context(|| format!("read {}", path.display()))?;
+            serde_json::from_slice(&data).with_context(|| format!("parse {}", path.display()))?
+        } else {
+            Vec::new()
+        };
+        Ok(Self {
+            path,
+            users: Mutex::new(users),
+        })
+    }
+
+    fn save(&self, users: &[UserRecord]) -> Result<()> {
+        write_atomic(&self.path, &serde_json::to_vec_pretty(users)?)
+    }
+}
+
+impl UserRepository for FileUserRepository {
+    fn find_by_name(&self, name: &str) -> Result<Option<Auth>> {
+        let users = self.users.lock().unwrap();
+        Ok(users.iter().find(|u| u.name == name).cloned().map(Auth::from))
+    }
+
+    fn find_by_id(&self, id: u64) -> Result<Option<Auth>> {
+        let users = self.users.lock().unwrap();
+        Ok(users.iter().find(|u| u.id == id).cloned().map(Auth::from))
+    }
+
+    fn list(&self) -> Result<Vec<Auth>> {
+        let users = self.users.lock().unwrap();
+        Ok(users.iter().cloned().map(Auth::from).collect())
+    }
+
+    fn insert(&self, mut user: Auth) -> Result<Auth> {
+        let mut users = self.users.lock().unwrap();
+        if users.iter().any(|u| u.name == user.name) {
+            return Err(anyhow!("user {} already exists", user.name));
+        }
+        if user.id == 0 {
+            user.id = users.iter().map(|u| u.id).max().unwrap_or(0) + 1;
+        } else if users.iter().any(|u| u.id == user.id) {
+            return Err(anyhow!("user id {} already exists", user.id));
+        }
+        users.push(UserRecord::from(&user));
+        if let Err(e) = self.save(&users) {
+            users.pop();
+            return Err(e);
+        }
+        Ok(user)
+    }
+
+    fn update_with(&self, id: u64, f: &mut dyn FnMut(&mut Auth) -> Result<()>) -> Result<Auth> {
+        let mut users = self.users.lock().unwrap();
+        let index = users
+            .iter()
+            .position(|u| u.id == id)
+            .ok_or_else(|| anyhow!("user {} not found", id))?;
+        let mut user = Auth::from(users[index].clone());
+        f(&mut user)?;
+        user.id = id;
+        let previous = std::mem::replace(&mut users[index], UserRecord::from(&user));
+        if let Err(e) = self.save(&users) {
+            users[index] = previous;
+            return Err(e);
+        }
+        Ok(user)
+    }
+}
+
+/// Writes to a sibling temp file first so a crash never leaves a truncated
+/// file behind.
+pub(crate) fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Imports the `[seed_user]`, formerly the single user of the `CLIENT_*`
+/// variables, so existing deployments keep working after the switch to the
+/// repository. That user becomes the first administrator.
+fn seed(repo: &dyn UserRepository, seed: &SeedUserConfig) -> Result<()> {
+    let SeedUserConfig {
+        name: Some(name),
+        id: Some(id),
+        password: Some(password),
+        client_salt: Some(client_salt),
+        server_salt: Some(server_salt),
+    } = seed.clone()
+    else {
+        return Ok(());
+    };
+    if repo.find_by_name(&name)?.is_some() {
+        return Ok(());
+    }
+    let salt = seed_client_salt(&client_salt);
+    if salt != client_salt {
+        warn!("seed user {} gets client salt {} in place of {}", name, salt, client_salt);
+    }
+    let mut user = Auth::new(name, id, password, salt, server_salt);
+    user.role = Role::Admin;
+    repo.insert(user)?;
+    Ok(())
+}
+
+/// Opens the user store at `storage.user_db_path` and seeds it.
+pub(crate) fn open(config: &Config) -> Result<Box<dyn UserRepository>> {
+    let repo = FileUserRepository::open(&config.storage.user_db_path)?;
+    seed(&repo, &config.seed_user).context("seed user repository")?;
+    Ok(Box::new(repo))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_assigns_ids_and_persists() {
+        let path = std::env::temp_dir().join(format!("users-{}.json", uuid::Uuid::new_v4()));
+        let repo = FileUserRepository::open(&path).unwrap();
+        let user = Auth::new("a".into(), 0, "p".into(), "c".into(), "s".into());
+        assert_eq!(repo.insert(user.clone()).unwrap().id, 1);
+        assert!(repo.insert(user).is_err());
+
+        let repo = FileUserRepository::open(&path).unwrap();
+        let user = repo.find_by_name("a").unwrap().unwrap();
+        assert_eq!(user.password, "p");
+        assert_eq!(repo.find_by_id(1).unwrap().unwrap().name, "a");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_failed_insert_leaves_no_record() {
+        let path = std::env::temp_dir().join(format!("users-{}.json", uuid::Uuid::new_v4()));
+        let repo = FileUserRepository::open(&path).unwrap();
+        // A directory in the way of the temp file makes the save fail.
+        fs::create_dir(path.with_extension("tmp")).unwrap();
+        let user = Auth::new("a".into(), 0, "p".into(), "c".into(), "s".into());
+        assert!(repo.insert(user.clone()).is_err());
+        assert!(repo.find_by_name("a").unwrap().is_none());
+
+        fs::remove_dir(path.with_extension("tmp")).unwrap();
+        assert_eq!(repo.insert(user).unwrap().id, 1);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_update_with_keeps_failed_changes_out() {
+        let path = std::env::temp_dir().join(format!("users-{}.json", uuid::Uuid::new_v4()));
+        let repo = FileUserRepository::open(&path).unwrap();
+        let user = Auth::new("a".into(), 0, "p".into(), "c".into(), "s".into());
+        let id = repo.insert(user).unwrap().id;
+
+        let updated = repo.update_with(id, &mut |u| {
+            u.password = "q".into();
+            Ok(())
+        });
+        assert_eq!(updated.unwrap().password, "q");
+        let failed = repo.update_with(id, &mut |u| {
+            u.password = "r".into();
+            Err(anyhow!("rejected"))
+        });
+        assert!(failed.is_err());
+        assert!(repo.update_with(id + 1, &mut |_| Ok(())).is_err());
+
+        let repo = FileUserRepository::open(&path).unwrap();
+        assert_eq!(repo.find_by_id(id).unwrap().unwrap().password, "q");
+        fs::remove_file(path).unwrap();
+    }
+}
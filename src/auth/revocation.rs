@@ -0,0 +1,142 @@
+use std::{collections::HashMap, fs, path::PathBuf, sync::Mutex};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::repository::write_atomic;
+
+/// Tracks refresh token families. Every login starts a family; each refresh
+/// replaces the family's current refresh token id, so a refresh token can be
+/// used exactly once. Presenting a stale one means it was stolen or replayed,
+/// and the whole family is revoked.
+pub trait RevocationStore: Send + Sync {
+    fn start_family(&self, fid: &str, uid: u64, jti: &str, exp: i64) -> Result<()>;
+    /// Swaps `old_jti` for `new_jti`, or revokes the family on reuse.
+    fn rotate(&self, fid: &str, old_jti: &str, new_jti: &str, exp: i64) -> Result<()>;
+    fn revoke_family(&self, fid: &str) -> Result<()>;
+    /// Revokes every family of `uid` and returns how many were live.
+    fn revoke_user(&self, uid: u64) -> Result<usize>;
+    fn is_revoked(&self, fid: &str) -> bool;
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct Family {
+    uid: u64,
+    current: String,
+    exp: i64,
+    revoked: bool,
+}
+
+pub struct FileRevocationStore {
+    path: PathBuf,
+    families: Mutex<HashMap<String, Family>>,
+}
+
+impl FileRevocationStore {
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let families = if path.exists() {
+            let data = fs::read(&path).with_context(|| format!("read {}", path.display()))?;
+            serde_json::from_slice(&data).with_context(|| format!("parse {}", path.display()))?
+        } else {
+            HashMap::new()
+        };
+        Ok(Self {
+            path,
+            families: Mutex::new(families),
+        })
+    }
+
+    /// Drops families whose refresh token has expired anyway, then persists.
+    fn save(&self, families: &mut HashMap<String, Family>) -> Result<()> {
+        let now = chrono::Utc::now().timestamp();
+        families.retain(|_, f| f.exp >= now);
+        write_atomic(&self.path, &serde_json::to_vec(families)?)
+    }
+}
+
+impl RevocationStore for FileRevocationStore {
+    fn start_family(&self, fid: &str, uid: u64, jti: &str, exp: i64) -> Result<()> {
+        let mut families = self.families.lock().unwrap();
+        families.insert(
+            fid.to_owned(),
+            Family {
+                uid,
+                current: jti.to_owned(),
+                exp,
+                revoked: false,
+            },
+        );
+        self.save(&mut families)
+    }
+
+    fn rotate(&self, fid: &str, old_jti: &str, new_jti: &str, exp: i64) -> Result<()> {
+        let mut families = self.families.lock().unwrap();
+        let family = families
+            .get_mut(fid)
+            .ok_or_else(|| anyhow!("unknown token family"))?;
+        if family.revoked {
+            return Err(anyhow!("token family revoked"));
+        }
+        if family.current != old_jti {
+            tracing::warn!(
+                "refresh token reuse detected for user {}, revoking family {}",
+                family.uid,
+                fid
+            );
+            family.revoked = true;
+            self.save(&mut families)?;
+            return Err(anyhow!("refresh token reused"));
+        }
+        family.current = new_jti.to_owned();
+        family.exp = exp;
+        self.save(&mut families)
+    }
+
+    fn revoke_family(&self, fid: &str) -> Result<()> {
+        let mut families = self.families.lock().unwrap();
+        if let Some(family) = families.get_mut(fid) {
+            family.revoked = true;
+        }
+        self.save(&mut families)
+    }
+
+    fn revoke_user(&self, uid: u64) -> Result<usize> {
+        let mut families = self.families.lock().unwrap();
+        let mut count = 0;
+        for family in families.values_mut().filter(|f| f.uid == uid && !f.revoked) {
+            family.revoked = true;
+            count += 1;
+        }
+        self.save(&mut families)?;
+        Ok(count)
+    }
+
+    fn is_revoked(&self, fid: &str) -> bool {
+        self.families
+            .lock()
+            .unwrap()
+            .get(fid)
+            .is_none_or(|f| f.revoked)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reuse_revokes_family() {
+        let path = std::env::temp_dir().join(format!("tokens-{}.json", uuid::Uuid::new_v4()));
+        let store = FileRevocationStore::open(&path).unwrap();
+        let exp = chrono::Utc::now().timestamp() + 60;
+        store.start_family("f", 1, "a", exp).unwrap();
+        store.rotate("f", "a", "b", exp).unwrap();
+        assert!(!store.is_revoked("f"));
+
+        assert!(store.rotate("f", "a", "c", exp).is_err());
+        assert!(store.is_revoked("f"));
+        assert!(store.rotate("f", "b", "c", exp).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,29 @@
+use serde::{Deserialize, Serialize};
+
+/// What an account may do. Each role includes the permissions of the roles
+/// below it: admin > parent > child.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// A profile managed by a parent. Also assumed for tokens issued before
+    /// roles existed, as the least privileged choice.
+    #[default]
+    Child,
+    /// A family or classroom account that manages its children.
+    Parent,
+    Admin,
+}
+
+impl Role {
+    const fn rank(self) -> u8 {
+        match self {
+            Self::Child => 0,
+            Self::Parent => 1,
+            Self::Admin => 2,
+        }
+    }
+
+    pub const fn allows(self, required: Role) -> bool {
+        self.rank() >= required.rank()
+    }
+}
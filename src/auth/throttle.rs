@@ -0,0 +1,195 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use crate::config::LoginConfig;
+
+/// Limits for [`LoginThrottle`].
+#[derive(Debug, Clone)]
+pub struct ThrottleConfig {
+    /// Failures allowed before any delay is imposed.
+    pub free_attempts: u32,
+    /// Lockout after the first failure past `free_attempts`, doubled for
+    /// every further failure.
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// Failures older than this are forgotten. Running lockouts still end
+    /// at their own time.
+    pub window: Duration,
+}
+
+impl From<&LoginConfig> for ThrottleConfig {
+    fn from(login: &LoginConfig) -> Self {
+        Self {
+            free_attempts: login.free_attempts,
+            base_delay: Duration::from_secs(login.backoff_base_secs),
+            max_delay: Duration::from_secs(login.lockout_max_secs),
+            window: Duration::from_secs(login.failure_window_secs),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Name(String),
+    Ip(IpAddr),
+}
+
+#[derive(Debug)]
+struct Attempts {
+    failures: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Tracks failed logins per username and per client address.
+pub struct LoginThrottle {
+    config: RwLock<ThrottleConfig>,
+    attempts: Mutex<HashMap<Key, Attempts>>,
+}
+
+impl LoginThrottle {
+    pub fn new(config: ThrottleConfig) -> Self {
+        Self {
+            config: RwLock::new(config),
+            attempts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Applies new limits. Running lockouts keep their end time.
+    pub fn set_config(&self, config: ThrottleConfig) {
+        *self.config.write().unwrap() = config;
+    }
+
+    /// Returns how long the caller has to wait when either the name or the
+    /// address is locked out.
+    pub fn check(&self, name: &str, ip: IpAddr) -> Option<Duration> {
+        let now = Instant::now();
+        let attempts = self.attempts.lock().unwrap();
+        [Key::Name(name.to_owned()), Key::Ip(ip)]
+            .iter()
+            .filter_map(|key| attempts.get(key)?.locked_until)
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+            .max()
+    }
+
+    pub fn record_failure(&self, name: &str, ip: IpAddr) {
+        let now = Instant::now();
+        let config = self.config.read().unwrap().clone();
+        let mut attempts = self.attempts.lock().unwrap();
+        attempts.retain(|_, a| {
+            now - a.last_failure < config.window || a.locked_until.is_some_and(|t| t > now)
+        });
+        for key in [Key::Name(name.to_owned()), Key::Ip(ip)] {
+            let entry = attempts.entry(key).or_insert(Attempts {
+                failures: 0,
+                last_failure: now,
+                locked_until: None,
+            });
+            entry.failures += 1;
+            entry.last_failure = now;
+            if let Some(over) = entry.failures.checked_sub(config.free_attempts.saturating_add(1)) {
+                let delay = config
+                    .base_delay
+                    .saturating_mul(2u32.saturating_pow(over))
+                    .min(config.max_delay);
+                entry.locked_until = Some(now + delay);
+            }
+        }
+    }
+
+    /// Forgets the failures of `name`. Those of the address are left to
+    /// expire, a valid login for one account must not reset guessing at
+    /// others from the same place.
+    pub fn record_success(&self, name: &str) {
+        let mut attempts = self.attempts.lock().unwrap();
+        attempts.remove(&Key::Name(name.to_owned()));
+    }
+
+    /// Clears the state of a username and/or address. Returns whether
+    /// anything was removed.
+    pub fn unlock(&self, name: Option<&str>, ip: Option<IpAddr>) -> bool {
+        let mut attempts = self.attempts.lock().unwrap();
+        let name = name.and_then(|n| attempts.remove(&Key::Name(n.to_owned())));
+        let ip = ip.and_then(|ip| attempts.remove(&Key::Ip(ip)));
+        name.is_some() || ip.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_and_unlock() {
+        let throttle = LoginThrottle::new(ThrottleConfig {
+            free_attempts: 2,
+            base_delay: Duration::from_secs(10),
+            max_delay: Duration::from_secs(25),
+            window: Duration::from_secs(60),
+        });
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let other: IpAddr = "127.0.0.2".parse().unwrap();
+        throttle.record_failure("a", ip);
+        throttle.record_failure("a", ip);
+        assert!(throttle.check("a", ip).is_none());
+
+        throttle.record_failure("a", ip);
+        let wait = throttle.check("a", other).unwrap();
+        assert!(wait <= Duration::from_secs(10) && wait > Duration::from_secs(9));
+        throttle.record_failure("a", ip);
+        throttle.record_failure("a", ip);
+        assert!(throttle.check("b", ip).unwrap() > Duration::from_secs(24));
+
+        assert!(throttle.unlock(Some("a"), Some(ip)));
+        assert!(throttle.check("a", ip).is_none());
+    }
+
+    #[test]
+    fn test_success_keeps_address_failures() {
+        let throttle = LoginThrottle::new(ThrottleConfig {
+            free_attempts: 1,
+            base_delay: Duration::from_secs(10),
+            max_delay: Duration::from_secs(10),
+            window: Duration::from_secs(60),
+        });
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        throttle.record_failure("a", ip);
+        throttle.record_failure("b", ip);
+        assert!(throttle.check("a", ip).is_some());
+
+        throttle.record_success("a");
+        let other: IpAddr = "127.0.0.2".parse().unwrap();
+        assert!(throttle.check("a", other).is_none());
+        assert!(throttle.check("c", ip).is_some());
+    }
+
+    #[test]
+    fn test_lockout_outlives_window() {
+        let throttle = LoginThrottle::new(ThrottleConfig {
+            free_attempts: 0,
+            base_delay: Duration::from_secs(10),
+            max_delay: Duration::from_secs(10),
+            window: Duration::ZERO,
+        });
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let other: IpAddr = "127.0.0.2".parse().unwrap();
+        throttle.record_failure("a", ip);
+        // Expires the failures of "a", but not its lockout.
+        throttle.record_failure("b", other);
+        assert!(throttle.check("a", other).is_some());
+
+        throttle.set_config(ThrottleConfig {
+            free_attempts: u32::MAX,
+            base_delay: Duration::from_secs(10),
+            max_delay: Duration::from_secs(10),
+            window: Duration::ZERO,
+        });
+        throttle.record_failure("c", other);
+        assert!(throttle.check("c", "127.0.0.3".parse().unwrap()).is_none());
+    }
+}
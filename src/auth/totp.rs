@@ -0,0 +1,361 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use anyhow::{anyhow, Result};
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, thread_rng, Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+use super::{hash, Auth, JWTToken, Role};
+use crate::state::AppState;
+
+const STEP: u64 = 30;
+const DIGITS: u32 = 6;
+/// Accepted clock drift in steps on either side.
+const SKEW: u64 = 1;
+const RECOVERY_CODES: usize = 10;
+const CHALLENGE_EXPIRE: i64 = 5 * 60;
+const CHALLENGE_PURPOSE: &str = "2fa";
+
+/// TOTP settings of a user as stored in the repository.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct TotpState {
+    /// Base32 encoded shared secret.
+    pub secret: String,
+    /// `false` until the user confirmed enrollment with a valid code.
+    pub enabled: bool,
+    /// Last accepted time step, codes from it or earlier are replays.
+    pub last_step: u64,
+    /// Blake2b digests of the unused recovery codes.
+    pub recovery_codes: Vec<String>,
+}
+
+/// Returned by `/api/2fa/enroll` so the client can show a QR code.
+#[derive(Debug, Clone, Serialize)]
+pub struct TotpEnrollment {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+/// Second login step for users with TOTP enabled.
+#[derive(Debug, Clone, Serialize)]
+pub struct TwoFactorChallenge {
+    pub challenge_token: String,
+}
+
+/// Claims of a challenge token.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Challenge {
+    id: u64,
+    exp: i64,
+    jti: String,
+    purpose: String,
+}
+
+/// Ids of redeemed challenge tokens, kept until the tokens expire. Only in
+/// memory: after a restart a replayed token still needs an unused code.
+#[derive(Default)]
+pub struct RedeemedChallenges(Mutex<HashMap<String, i64>>);
+
+impl RedeemedChallenges {
+    fn contains(&self, challenge: &Challenge) -> bool {
+        self.0.lock().unwrap().contains_key(&challenge.jti)
+    }
+
+    /// Returns `false` if `challenge` was redeemed before.
+    fn redeem(&self, challenge: &Challenge) -> bool {
+        let now = chrono::Utc::now().timestamp();
+        let mut redeemed = self.0.lock().unwrap();
+        redeemed.retain(|_, exp| *exp >= now);
+        redeemed
+            .insert(challenge.jti.clone(), challenge.exp)
+            .is_none()
+    }
+}
+
+fn code_at(secret: &[u8], step: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("hmac accepts any key length");
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest[19] & 0x0f) as usize;
+    let value = u32::from_be_bytes(digest[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+    value % 10u32.pow(DIGITS)
+}
+
+/// Returns the matching time step for `code` within the allowed skew.
+fn verify_code(secret: &[u8], code: &str, now: u64) -> Option<u64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let current = now / STEP;
+    (current.saturating_sub(SKEW)..=current + SKEW).find(|step| code_at(secret, *step) == code)
+}
+
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+fn otpauth_uri(issuer: &str, account: &str, secret: &str) -> String {
+    let issuer = percent_encode(issuer);
+    format!(
+        "otpauth://totp/{issuer}:{}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={STEP}",
+        percent_encode(account)
+    )
+}
+
+fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let code: String = thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(10)
+                .map(|c| char::from(c).to_ascii_lowercase())
+                .collect();
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect()
+}
+
+impl TotpState {
+    fn secret_bytes(&self) -> Result<Vec<u8>> {
+        BASE32_NOPAD
+            .decode(self.secret.as_bytes())
+            .map_err(|e| anyhow!("invalid totp secret: {}", e))
+    }
+
+    /// Accepts a current code once, or consumes a recovery code.
+    fn verify(&mut self, code: &str) -> bool {
+        let now = chrono::Utc::now().timestamp() as u64;
+        if let Some(step) = self
+            .secret_bytes()
+            .ok()
+            .and_then(|secret| verify_code(&secret, code, now))
+        {
+            if step > self.last_step {
+                self.last_step = step;
+                return true;
+            }
+            return false;
+        }
+        let digest = hash(&code.trim().to_ascii_lowercase());
+        let used = self
+            .recovery_codes
+            .iter()
+            .position(|c| bool::from(c.as_bytes().ct_eq(digest.as_bytes())));
+        if let Some(index) = used {
+            self.recovery_codes.remove(index);
+            return true;
+        }
+        false
+    }
+}
+
+impl Auth {
+    pub fn totp_enabled(&self) -> bool {
+        self.totp.as_ref().is_some_and(|t| t.enabled)
+    }
+
+    /// The role the account acts with. Without TOTP, parents and
+    /// administrators only get child rights while `auth.require_second_factor`
+    /// is set.
+    pub fn effective_role(&self, app: &AppState) -> Role {
+        if self.role.allows(Role::Parent)
+            && !self.totp_enabled()
+            && app.config.get().auth.require_second_factor
+        {
+            return Role::Child;
+        }
+        self.role
+    }
+
+    /// Starts (or restarts) enrollment with a fresh secret. TOTP stays off
+    /// until [`Auth::confirm_totp`] succeeds. Authenticator apps list the
+    /// account under `issuer`.
+    pub fn enroll_totp(&mut self, app: &AppState, issuer: &str) -> Result<TotpEnrollment> {
+        if self.totp_enabled() {
+            return Err(anyhow!("two-factor authentication is already enabled"));
+        }
+        let mut secret = [0u8; 20];
+        thread_rng().fill_bytes(&mut secret);
+        let secret = BASE32_NOPAD.encode(&secret);
+        *self = app.users.update_with(self.id, &mut |user| {
+            if user.totp_enabled() {
+                return Err(anyhow!("two-factor authentication is already enabled"));
+            }
+            user.totp = Some(TotpState {
+                secret: secret.clone(),
+                ..Default::default()
+            });
+            Ok(())
+        })?;
+        Ok(TotpEnrollment {
+            otpauth_uri: otpauth_uri(issuer, &self.name, &secret),
+            secret,
+        })
+    }
+
+    /// Enables TOTP after checking a code from the enrolled authenticator and
+    /// returns the recovery codes. They are only stored hashed.
+    pub fn confirm_totp(&mut self, app: &AppState, code: &str) -> Result<Vec<String>> {
+        let codes = generate_recovery_codes();
+        *self = app.users.update_with(self.id, &mut |user| {
+            let totp = user
+                .totp
+                .as_mut()
+                .filter(|t| !t.enabled)
+                .ok_or_else(|| anyhow!("no pending two-factor enrollment"))?;
+            if !totp.verify(code) {
+                return Err(anyhow!("invalid code"));
+            }
+            totp.recovery_codes = codes.iter().map(|c| hash(c)).collect();
+            totp.enabled = true;
+            Ok(())
+        })?;
+        Ok(codes)
+    }
+
+    pub fn disable_totp(&mut self, app: &AppState, password: &str, code: &str) -> Result<()> {
+        if !self.check(&self.name, password) {
+            return Err(anyhow!("invalid password"));
+        }
+        *self = app.users.update_with(self.id, &mut |user| {
+            user.consume_second_factor(code)?;
+            user.totp = None;
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    /// Checks a TOTP or recovery code against the stored state and persists
+    /// the replay/consumption state in the same step, so a code is accepted
+    /// only once even by concurrent requests.
+    fn verify_second_factor(&mut self, app: &AppState, code: &str) -> Result<()> {
+        *self = app
+            .users
+            .update_with(self.id, &mut |user| user.consume_second_factor(code))?;
+        Ok(())
+    }
+
+    fn consume_second_factor(&mut self, code: &str) -> Result<()> {
+        let valid = self
+            .totp
+            .as_mut()
+            .filter(|t| t.enabled)
+            .is_some_and(|totp| totp.verify(code));
+        if !valid {
+            return Err(anyhow!("invalid code"));
+        }
+        Ok(())
+    }
+
+    pub fn two_factor_challenge(&self, app: &AppState) -> Result<TwoFactorChallenge> {
+        let claims = Challenge {
+            id: self.id,
+            exp: chrono::Utc::now().timestamp() + CHALLENGE_EXPIRE,
+            jti: Uuid::new_v4().to_string(),
+            purpose: CHALLENGE_PURPOSE.into(),
+        };
+        let challenge_token = app.keys.refresh.encode(&claims)?;
+        Ok(TwoFactorChallenge { challenge_token })
+    }
+
+    /// Resolves the user a challenge token was issued to.
+    pub fn from_challenge_token(app: &AppState, token: &str) -> Result<(Self, Challenge)> {
+        let claims = app.keys.refresh.decode::<Challenge>(token)?;
+        if claims.purpose != CHALLENGE_PURPOSE {
+            return Err(anyhow!("not a challenge token"));
+        }
+        Ok((Self::new_by_id(app, claims.id)?, claims))
+    }
+
+    /// Second login step: trades a valid code for the token pair. Each
+    /// challenge can be redeemed once.
+    pub fn complete_login(
+        &mut self,
+        app: &AppState,
+        challenge: &Challenge,
+        code: &str,
+    ) -> Result<JWTToken> {
+        if app.challenges.contains(challenge) {
+            return Err(anyhow!("challenge already used"));
+        }
+        self.verify_second_factor(app, code)?;
+        if !app.challenges.redeem(challenge) {
+            return Err(anyhow!("challenge already used"));
+        }
+        self.generate_token(app)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rfc6238_vectors() {
+        let secret = b"12345678901234567890";
+        assert_eq!(code_at(secret, 59 / STEP), 287082);
+        assert_eq!(code_at(secret, 1111111109 / STEP), 81804);
+        assert_eq!(code_at(secret, 2000000000 / STEP), 279037);
+        assert_eq!(verify_code(secret, "279037", 2000000000 + 30), Some(2000000000 / STEP));
+        assert_eq!(verify_code(secret, "279037", 2000000000 + 90), None);
+    }
+
+    #[test]
+    fn test_replay_and_recovery_codes() {
+        let secret = b"12345678901234567890";
+        let mut state = TotpState {
+            secret: BASE32_NOPAD.encode(secret),
+            enabled: true,
+            last_step: 0,
+            recovery_codes: vec![hash("abcde-fghij")],
+        };
+        let now = chrono::Utc::now().timestamp() as u64;
+        let code = format!("{:06}", code_at(secret, now / STEP));
+        assert!(state.verify(&code));
+        assert!(!state.verify(&code));
+        assert!(state.verify("ABCDE-FGHIJ"));
+        assert!(!state.verify("abcde-fghij"));
+    }
+
+    #[test]
+    fn test_challenge_is_redeemed_once() {
+        let app = AppState::in_temp_dir();
+        let salt = Uuid::new_v4().to_string();
+        let user = Auth::create(&app, "ann".into(), "pw", salt, Role::Parent, None).unwrap();
+        assert_eq!(user.effective_role(&app), Role::Child);
+        let mut user = app
+            .users
+            .update_with(user.id, &mut |u| {
+                u.totp = Some(TotpState {
+                    secret: BASE32_NOPAD.encode(b"12345678901234567890"),
+                    enabled: true,
+                    last_step: 0,
+                    recovery_codes: vec![hash("abcde-fghij"), hash("klmno-pqrst")],
+                });
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(user.effective_role(&app), Role::Parent);
+
+        let token = user.two_factor_challenge(&app).unwrap().challenge_token;
+        let (_, challenge) = Auth::from_challenge_token(&app, &token).unwrap();
+        assert!(user.complete_login(&app, &challenge, "wrong").is_err());
+        assert!(user.complete_login(&app, &challenge, "abcde-fghij").is_ok());
+        assert!(user.complete_login(&app, &challenge, "klmno-pqrst").is_err());
+        let stored = app.users.find_by_id(user.id).unwrap().unwrap();
+        assert_eq!(stored.totp.unwrap().recovery_codes.len(), 1);
+    }
+}
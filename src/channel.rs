@@ -1,154 +1,438 @@
-use std::{sync::Arc, default};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::Duration,
+};
 
-use anyhow::{anyhow, Result};
-use tracing::info;
+use anyhow::Result;
+use futures::{future::BoxFuture, stream::FuturesUnordered, FutureExt, StreamExt};
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
 use uuid::Uuid;
 
-type Sender<T> = tokio::sync::mpsc::UnboundedSender<T>;
-type Receiver<T> = tokio::sync::mpsc::UnboundedReceiver<T>;
+type Receiver<T> = tokio::sync::mpsc::Receiver<T>;
 
 use crate::{
-    utils::{azure_tts::fetch_speed, event, event::WsResponse, openai::en_teacher_chat},
-    ws,
+    config::{Config, ServerConfig},
+    handlers::{Context, Registry},
+    utils::event::{self, ErrorCode, ErrorDetail, WsResponse},
+    ws::{self, state::WsState},
 };
 
-pub async fn handle_message(
-    r: &mut Receiver<event::ChannelMessage>,
-    state: Arc<ws::state::WsState>,
-) {
+/// Pause before a crashed dispatcher is started again.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// Runs the dispatcher and starts it again whenever it panics. Requests it
+/// had queued are answered with an error in a crash, those it had started
+/// keep running.
+pub async fn handle_message(r: Receiver<event::ChannelMessage>, state: Arc<WsState>) {
+    let r = Arc::new(Mutex::new(r));
     loop {
-        while let Some(msg) = r.recv().await {
-            // println!("handle_message: {:?}", msg);
-            handle_message_item(msg, state.clone()).await.map_err(|e| {
-                tracing::error!("handle_message_item error: {:?}", e);
-                anyhow!(e)
-            }).unwrap();
+        match tokio::spawn(dispatch(r.clone(), state.clone())).await {
+            Ok(()) => return,
+            Err(e) => {
+                error!("dispatcher crashed, restarting: {}", e);
+                fail_waiting_requests(&state);
+                tokio::time::sleep(RESTART_DELAY).await;
+            }
         }
     }
 }
 
-async fn handle_message_item(
-    msg: event::ChannelMessage,
-    state: Arc<ws::state::WsState>,
-) -> Result<()> {
-    let msg_id = Arc::new(Uuid::new_v4().to_string());
-    info!("handle_message_item: {:?}", msg.body.event);
+/// Answers every request that has not started. The crashed dispatcher took
+/// its queue along, and those still in the channel are dropped as cancelled
+/// once received.
+fn fail_waiting_requests(state: &WsState) {
+    let waiting = state.take_waiting_requests();
+    let peers = state.user_peer_map.lock().unwrap();
+    for (uuid, msg_id) in waiting {
+        let Some(peer) = peers.get(&uuid) else {
+            continue;
+        };
+        let error = ErrorDetail::new(ErrorCode::Internal, "internal error");
+        send_failure(&peer.sender, peer.session.uid, &msg_id, error);
+    }
+}
 
-    let uuids = if msg.body.to == 0 {
-        Some(vec![msg.uuid.clone()])
-    } else {
-        state.get_user_uuid_map(msg.body.to)
-    };
+/// Requests of one user.
+struct UserQueue<T> {
+    waiting: VecDeque<T>,
+    running: usize,
+}
+
+/// Round-robin over users: each turn starts at most one request of a user,
+/// so a burst of one user waits behind a single request of everyone else.
+struct Scheduler<T> {
+    users: HashMap<u64, UserQueue<T>>,
+    /// Users with waiting requests, in the order of their next turn.
+    turns: VecDeque<u64>,
+    waiting: usize,
+    running: usize,
+}
+
+impl<T> Scheduler<T> {
+    fn new() -> Self {
+        Self {
+            users: HashMap::new(),
+            turns: VecDeque::new(),
+            waiting: 0,
+            running: 0,
+        }
+    }
 
-    if uuids.is_none() {
-        info!("user {} not found", msg.body.to.to_string());
-        return Ok(());
+    /// Queues `item` unless `uid` already has `max_waiting` requests waiting.
+    fn enqueue(&mut self, uid: u64, item: T, max_waiting: usize) -> Result<(), T> {
+        let user = self.users.entry(uid).or_insert_with(|| UserQueue {
+            waiting: VecDeque::new(),
+            running: 0,
+        });
+        if user.waiting.len() >= max_waiting {
+            return Err(item);
+        }
+        if user.waiting.is_empty() {
+            self.turns.push_back(uid);
+        }
+        user.waiting.push_back(item);
+        self.waiting += 1;
+        Ok(())
     }
 
-    for uuid in uuids.unwrap() {
-        let sender = state.get_user_peer_map(uuid.clone());
-        if sender.is_none() {
-            info!("user {} not found", msg.body.to.to_string());
-            continue;
+    /// Next request to start while fewer than `max_running` run in total and
+    /// fewer than `max_user_running` for its user.
+    fn next(&mut self, max_running: usize, max_user_running: usize) -> Option<(u64, T)> {
+        if self.running >= max_running {
+            return None;
         }
-        let sender = sender.unwrap();
-        let msg_id = msg_id.clone();
-        let msg = Arc::new(msg.body.clone());
-        tokio::spawn(async move {
-            let msg = msg.clone();
-            match msg.to {
-                0 => {
-                    handle_system_message(msg, msg_id.clone(), &sender)
-                        .await
-                        .map_err(|e| {
-                            tracing::error!("handle_system_message error: {:?}", e);
-                            anyhow!("handle_system_message error: {:?}", e)
-                        })?;
-                }
-                _ => {
-                    sender.send(msg).map_err(|e| {
-                        tracing::error!("handle_system_message error: {:?}", e);
-                        anyhow!("handle_system_message error: {:?}", e)
-                    })?;
+        for _ in 0..self.turns.len() {
+            let uid = self.turns.pop_front()?;
+            let user = self.users.get_mut(&uid)?;
+            if user.running >= max_user_running {
+                self.turns.push_back(uid);
+                continue;
+            }
+            let item = user.waiting.pop_front()?;
+            if !user.waiting.is_empty() {
+                self.turns.push_back(uid);
+            }
+            user.running += 1;
+            self.running += 1;
+            self.waiting -= 1;
+            return Some((uid, item));
+        }
+        None
+    }
+
+    fn finished(&mut self, uid: u64) {
+        let Some(user) = self.users.get_mut(&uid) else {
+            return;
+        };
+        user.running -= 1;
+        self.running -= 1;
+        if user.running == 0 && user.waiting.is_empty() {
+            self.users.remove(&uid);
+        }
+    }
+}
+
+/// Moves requests from the channel into the [`Scheduler`] and starts them
+/// within the limits of `[server]`, which are read again for every request so
+/// a reload applies right away. Returns once every sender is gone.
+async fn dispatch(r: Arc<Mutex<Receiver<event::ChannelMessage>>>, state: Arc<WsState>) {
+    let mut r = r.lock().await;
+    let mut scheduler = Scheduler::new();
+    let mut running = FuturesUnordered::<BoxFuture<'static, u64>>::new();
+    loop {
+        let config = state.config.get();
+        // Past the limit requests stay in the channel, which then rejects
+        // new ones as busy.
+        let accepting = scheduler.waiting < config.server.dispatch_queue;
+        tokio::select! {
+            msg = r.recv(), if accepting => match msg {
+                // Relays are cheap, only requests to the server are limited.
+                Some(msg) if msg.body.to != 0 => relay(&state, msg),
+                Some(msg) => {
+                    let max_waiting = config.server.dispatch_user_queue;
+                    if let Err(msg) = scheduler.enqueue(msg.uid, msg, max_waiting) {
+                        warn!("user {} has too many requests waiting", msg.uid);
+                        if !state.finish_request(&msg.uuid, &msg.body.msg_id) {
+                            continue;
+                        }
+                        let error = ErrorDetail::new(
+                            ErrorCode::TooManyRequests,
+                            "too many requests waiting",
+                        );
+                        reply_error(&state, &msg.uuid, msg.uid, &msg.body.msg_id, error);
+                    }
                 }
-            };
-            anyhow::Ok(())
+                None => return,
+            },
+            Some(uid) = running.next(), if !running.is_empty() => scheduler.finished(uid),
+        }
+        start_requests(&config.server, &state, &mut scheduler, &mut running);
+    }
+}
+
+fn start_requests(
+    config: &ServerConfig,
+    state: &Arc<WsState>,
+    scheduler: &mut Scheduler<event::ChannelMessage>,
+    running: &mut FuturesUnordered<BoxFuture<'static, u64>>,
+) {
+    let (max_running, max_user_running) =
+        (config.dispatch_concurrency, config.dispatch_user_concurrency);
+    while let Some((uid, msg)) = scheduler.next(max_running, max_user_running) {
+        let (uuid, msg_id) = (msg.uuid.clone(), msg.body.msg_id.clone());
+        let task = state.start_request(&uuid, &msg_id, || {
+            state.shutdown.spawn(handle_message_item(msg, state.clone()))
         });
+        let Some(task) = task else {
+            // Cancelled while it waited.
+            scheduler.finished(uid);
+            continue;
+        };
+        let state = state.clone();
+        // A panic only takes down its own task, the client still gets an
+        // answer. A cancelled one was answered by the cancel.
+        running.push(
+            async move {
+                match task.await {
+                    Err(e) if e.is_panic() && state.finish_request(&uuid, &msg_id) => {
+                        error!("request of user {} failed: {}", uid, e);
+                        let error = ErrorDetail::new(ErrorCode::Internal, "internal error");
+                        reply_error(&state, &uuid, uid, &msg_id, error);
+                    }
+                    _ => {}
+                }
+                uid
+            }
+            .boxed(),
+        );
     }
-    anyhow::Ok(())
 }
 
-async fn handle_system_message(
-    msg: Arc<event::WsRequest>,
-    msg_id: Arc<String>,
-    sender: &Sender<Arc<event::WsRequest>>,
-) -> Result<()> {
-    let resp = WsResponse {
-        event: event::Event::Loading(true),
-        event_type: event::EventType::Loading,
-        msg_id: msg_id.to_string(),
-        from: 0,
-        to: msg.from,
-        reply_msg_id: Some(msg.msg_id.clone()),
+/// Answers the request `reply_msg_id` with `error` on the socket it came
+/// from.
+fn reply_error(
+    state: &WsState,
+    uuid: &Arc<Uuid>,
+    uid: u64,
+    reply_msg_id: &str,
+    error: ErrorDetail,
+) {
+    if let Some(sender) = state.get_user_peer_map(uuid.clone()) {
+        send_failure(&sender, uid, reply_msg_id, error);
+    }
+}
+
+fn send_failure(sender: &ws::Outbox, to: u64, reply_msg_id: &str, error: ErrorDetail) {
+    for resp in WsResponse::failure(to, reply_msg_id, error) {
+        let _ = sender.send(Arc::new(resp));
+    }
+}
+
+/// Handles a request to the server on behalf of the socket it came from.
+async fn handle_message_item(msg: event::ChannelMessage, state: Arc<WsState>) {
+    info!("handle_message_item: user {} {:?}", msg.uid, msg.body.event);
+    let Some(sender) = state.get_user_peer_map(msg.uuid.clone()) else {
+        info!("socket of user {} is gone", msg.uid);
+        state.finish_request(&msg.uuid, &msg.body.msg_id);
+        return;
     };
-    sender.send(Arc::new(resp))?;
-    let sender = sender.clone();
-    let msg_id = msg_id.to_string();
-    tokio::spawn(async move {
-        let resp = handle_system_message_item(msg, msg_id.clone().to_string())
-            .await
-            .map_err(|e| {
-                tracing::error!("handle_system_message_item error: {:?}", e);
-                anyhow!(e)
-            })?;
-        println!("{:#?}", resp);
-        sender.clone().send(Arc::new(resp)).map_err(|e| {
-            tracing::error!("handle_system_message_item error: {:?}", e);
-            anyhow!(e)
-        })?;
-        anyhow::Ok(())
-    });
-    Ok(())
+    let msg_id = Uuid::new_v4().to_string();
+    handle_system_message(&state, &msg.uuid, &msg.body, msg_id, &sender).await
+}
+
+/// Passes `msg` on to every socket of its addressee, or of everyone for
+/// [`event::BROADCAST`].
+fn relay(state: &WsState, msg: event::ChannelMessage) {
+    info!("relay: user {} to {} {:?}", msg.uid, msg.body.to, msg.body.event);
+    let uuids = if msg.body.to == event::BROADCAST {
+        Some(state.all_uuids())
+    } else {
+        state.get_user_uuid_map(msg.body.to)
+    };
+    let Some(uuids) = uuids else {
+        info!("user {} not found", msg.body.to);
+        return;
+    };
+    let body = Arc::new(msg.body);
+    for uuid in uuids {
+        let Some(sender) = state.get_user_peer_map(uuid) else {
+            info!("user {} not found", body.to);
+            continue;
+        };
+        if let Err(e) = sender.send(body.clone()) {
+            info!("relay to user {} failed: {}", body.to, e);
+        }
+    }
+}
+
+/// Answers a request to the server with `Loading(true)` and, once handled,
+/// with its result, or with an error and `Loading(false)`. Nothing follows
+/// `Loading(true)` once the request is cancelled, the cancel answers it.
+async fn handle_system_message(
+    state: &WsState,
+    uuid: &Arc<Uuid>,
+    msg: &event::WsRequest,
+    msg_id: String,
+    sender: &ws::Outbox,
+) {
+    if state.shutdown.is_stopping() {
+        if !state.finish_request(uuid, &msg.msg_id) {
+            return;
+        }
+        let error = ErrorDetail::new(ErrorCode::ShuttingDown, "server restarting");
+        return send_failure(sender, msg.from, &msg.msg_id, error);
+    }
+    let _ = sender.send(Arc::new(WsResponse::loading(msg.from, &msg.msg_id, true)));
+    let config = state.config.get();
+    let result = handle_system_message_item(&state.handlers, &config, msg, msg_id).await;
+    if !state.finish_request(uuid, &msg.msg_id) {
+        return;
+    }
+    match result {
+        Ok(resp) => {
+            let _ = sender.send(Arc::new(resp));
+        }
+        Err(e) => {
+            error!("handle_system_message_item error: {:?}", e);
+            // Anything but our own errors may carry details of the upstream
+            // request, so the client only learns that it failed.
+            let error = e.downcast::<ErrorDetail>().unwrap_or_else(|_| {
+                ErrorDetail::new(ErrorCode::UpstreamFailed, "upstream service failed")
+            });
+            send_failure(sender, msg.from, &msg.msg_id, error);
+        }
+    }
 }
 
 async fn handle_system_message_item(
-    msg: Arc<event::WsRequest>,
+    handlers: &Registry,
+    config: &Config,
+    msg: &event::WsRequest,
     msg_id: String,
 ) -> Result<event::WsResponse> {
-    let msg = msg.clone();
-    let mut resp = WsResponse {
-        event: msg.event.clone(),
-        event_type: event::EventType::Loading,
+    let handler = handlers
+        .get(&msg.event_type)
+        .ok_or_else(|| ErrorDetail::new(ErrorCode::UnknownEvent, "unknown event type"))?;
+    let ctx = Context {
+        config,
+        uid: msg.from,
+    };
+    let event = handler.handle(&ctx, &msg.event).await?;
+    Ok(WsResponse {
+        event,
+        event_type: handler.response_type(),
         msg_id,
         from: 0,
         to: msg.from,
-        reply_msg_id: None,
+        reply_msg_id: Some(msg.msg_id.clone()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::Role,
+        handlers::{mismatched, Schema, SystemHandler},
+        state::AppState,
+        utils::event::{Event, EventType, WsRequest},
     };
-    match msg.event.clone() {
-        event::Event::Chat(message) => {
-            let openai_key = std::env::var("OPENAI_API_KEY").expect("OPENAI_API_KEY not found");
-            let text = en_teacher_chat(&openai_key, &message).await?;
-            let res = text.choices.first().unwrap().message.content.clone();
-            // let res = "天空的英文是`sky`。它是指地球上大气层上方的空间，
-            // 通常是呈现蓝色或灰色的。\    这是它的英文例句：1. `The sky is so
-            // clear today, not a single cloud in \    sight.` 2. `When the sun
-            // sets, the sky turns into a beautiful mixture of \
-            //    pink, purple, and orange colors.`"
-            // .to_owned();
-            resp.event = event::Event::Chat(res.unwrap_or_default());
-            resp.event_type = event::EventType::Chat;
-        }
-        event::Event::Speech(message) => {
-            let azure_tts_key = std::env::var("AZURE_TTS_KEY").expect("AZURE_TTS_KEY not found");
-            let region = std::env::var("AZURE_TTS_REGION").expect("AZURE_TTS_REGION not found");
-            let path = fetch_speed(&azure_tts_key, &region, &message).await?;
-            resp.event = event::Event::Speech(path);
-            resp.event_type = event::EventType::Speech;
-            resp.reply_msg_id = Some(msg.msg_id.clone());
-        }
-        _ => {
-            return Err(anyhow::anyhow!("unknown event type"));
+
+    /// Answers `chat` with the text reversed.
+    struct Reverse;
+
+    #[axum::async_trait]
+    impl SystemHandler for Reverse {
+        fn event_type(&self) -> EventType {
+            EventType::Chat
         }
-    };
-    Ok(resp)
+
+        fn response_type(&self) -> EventType {
+            EventType::Speech
+        }
+
+        fn required_role(&self) -> Role {
+            Role::Child
+        }
+
+        fn schema(&self) -> Schema {
+            Schema {
+                request: serde_json::Value::Null,
+                response: serde_json::Value::Null,
+            }
+        }
+
+        async fn handle(&self, ctx: &Context<'_>, event: &Event) -> Result<Event> {
+            let Event::Chat(text) = event else {
+                return Err(mismatched(EventType::Chat));
+            };
+            Ok(Event::Speech(format!("{}:{}", ctx.uid, text.chars().rev().collect::<String>())))
+        }
+    }
+
+    fn request(event: Event, event_type: EventType) -> WsRequest {
+        WsRequest {
+            from: 7,
+            to: 0,
+            event,
+            event_type,
+            msg_id: "m1".into(),
+            reply_msg_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_registry_dispatch() {
+        let config = AppState::in_temp_dir().config.get();
+        let mut registry = Registry::default();
+        registry.register(Reverse);
+
+        let msg = request(Event::Chat("abc".into()), EventType::Chat);
+        let resp = handle_system_message_item(&registry, &config, &msg, "r1".into())
+            .await
+            .unwrap();
+        assert_eq!(resp.event, Event::Speech("7:cba".into()));
+        assert_eq!(resp.event_type, EventType::Speech);
+        assert_eq!((resp.to, resp.msg_id.as_str()), (7, "r1"));
+        assert_eq!(resp.reply_msg_id.as_deref(), Some("m1"));
+
+        let msg = request(Event::Speech("abc".into()), EventType::Speech);
+        let err = handle_system_message_item(&registry, &config, &msg, "r2".into())
+            .await
+            .unwrap_err();
+        let err = err.downcast::<ErrorDetail>().unwrap();
+        assert_eq!(err.code, ErrorCode::UnknownEvent);
+
+        let msg = request(Event::Loading(true), EventType::Chat);
+        let err = handle_system_message_item(&registry, &config, &msg, "r3".into())
+            .await
+            .unwrap_err();
+        assert_eq!(err.downcast::<ErrorDetail>().unwrap().code, ErrorCode::BadRequest);
+    }
+
+    #[test]
+    fn test_scheduler_takes_turns_within_limits() {
+        let mut scheduler = Scheduler::new();
+        for i in 0..4 {
+            scheduler.enqueue(1, i, 4).unwrap();
+        }
+        assert_eq!(scheduler.enqueue(1, 4, 4), Err(4));
+        scheduler.enqueue(2, 10, 4).unwrap();
+        scheduler.enqueue(3, 20, 4).unwrap();
+
+        assert_eq!(scheduler.next(3, 2), Some((1, 0)));
+        assert_eq!(scheduler.next(3, 2), Some((2, 10)));
+        assert_eq!(scheduler.next(3, 2), Some((3, 20)));
+        assert_eq!(scheduler.next(3, 2), None);
+
+        scheduler.finished(2);
+        scheduler.finished(3);
+        assert_eq!(scheduler.next(3, 2), Some((1, 1)));
+        assert_eq!(scheduler.next(3, 2), None);
+        scheduler.finished(1);
+        assert_eq!(scheduler.next(3, 2), Some((1, 2)));
+        assert_eq!((scheduler.waiting, scheduler.running), (1, 2));
+    }
 }
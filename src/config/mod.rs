@@ -0,0 +1,686 @@
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+
+use crate::{auth::Role, utils::queue::Overflow};
+
+mod reload;
+
+pub use reload::{reload_on_sighup, ReloadAttempt, ReloadStatus, SharedConfig};
+
+/// Used when neither `--config` nor `CONFIG_PATH` name a file. It may be
+/// missing, everything can also come from the environment.
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+/// Every setting of the server. It is assembled once at startup from, in
+/// increasing precedence, the TOML config file, environment variables and
+/// `--section.key value` command line flags. See `config.example.toml`.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub server: ServerConfig,
+    #[serde(default)]
+    pub tls: TlsConfig,
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub login: LoginConfig,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    #[serde(default)]
+    pub oidc: OidcConfig,
+    pub openai: OpenAiConfig,
+    pub azure_tts: AzureTtsConfig,
+    #[serde(default)]
+    pub seed_user: SeedUserConfig,
+    /// The merged layers this was built from, for diffing on reload.
+    #[serde(skip)]
+    source: toml::Table,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct ServerConfig {
+    /// TCP addresses to listen on, with TLS when `[tls]` is configured.
+    pub bind: Vec<SocketAddr>,
+    /// Also listen on this Unix domain socket, for a reverse proxy on the
+    /// same host. Client addresses are taken from `X-Forwarded-For`.
+    pub unix_socket: Option<PathBuf>,
+    /// Static files served for every path no route matches.
+    pub assets_dir: PathBuf,
+    /// Where synthesized speech is cached and served from, `assets_dir` if
+    /// unset.
+    pub audio_cache_dir: Option<PathBuf>,
+    /// Whether anyone may create an account through `/api/register`.
+    pub registration_enabled: bool,
+    /// Accept the legacy `?accessToken=` on WebSocket upgrades. It leaks the
+    /// token into access logs.
+    pub ws_query_token: bool,
+    /// Origins allowed to call the API from a browser, any if empty.
+    pub cors_origins: Vec<String>,
+    /// Requests waiting to be dispatched, across all sockets. Requests beyond
+    /// it are rejected with an error event.
+    pub dispatch_queue: usize,
+    /// Requests one user may have waiting. Requests beyond it are rejected
+    /// with an error event.
+    pub dispatch_user_queue: usize,
+    /// Requests handled at once, across all users.
+    pub dispatch_concurrency: usize,
+    /// Requests of one user handled at once.
+    pub dispatch_user_concurrency: usize,
+    /// Events waiting to be written to one socket.
+    pub socket_queue: usize,
+    /// What happens to a socket whose client reads slower than events come.
+    pub socket_overflow: Overflow,
+    /// How long a shutdown waits for in-flight requests before closing the
+    /// sockets anyway.
+    pub shutdown_timeout_secs: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: vec![SocketAddr::from(([0, 0, 0, 0], 3000))],
+            unix_socket: None,
+            assets_dir: "assets".into(),
+            audio_cache_dir: None,
+            registration_enabled: false,
+            ws_query_token: false,
+            cors_origins: Vec::new(),
+            dispatch_queue: 1024,
+            dispatch_user_queue: 16,
+            dispatch_concurrency: 16,
+            dispatch_user_concurrency: 2,
+            socket_queue: 64,
+            socket_overflow: Overflow::DropOldest,
+            shutdown_timeout_secs: 30,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn audio_cache_dir(&self) -> &Path {
+        self.audio_cache_dir.as_deref().unwrap_or(&self.assets_dir)
+    }
+}
+
+/// Built-in HTTPS, off unless both paths are set. The files are reloaded when
+/// they change, so renewed certificates need no restart.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct TlsConfig {
+    /// PEM certificate chain, leaf first.
+    pub cert_path: Option<PathBuf>,
+    /// PEM private key, PKCS#8, PKCS#1 or SEC1.
+    pub key_path: Option<PathBuf>,
+    /// How often the files are checked for changes.
+    pub reload_interval_secs: u64,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            cert_path: None,
+            key_path: None,
+            reload_interval_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+pub enum JwtAlgorithm {
+    #[default]
+    HS256,
+    EdDSA,
+    RS256,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    #[serde(default)]
+    pub jwt_algorithm: JwtAlgorithm,
+    /// Signs access tokens with HS256. With an asymmetric algorithm, tokens
+    /// signed with it are still accepted.
+    pub jwt_secret: Option<String>,
+    #[serde(default)]
+    pub jwt_previous_secrets: Vec<String>,
+    pub jwt_refresh_secret: Option<String>,
+    #[serde(default)]
+    pub jwt_previous_refresh_secrets: Vec<String>,
+    pub jwt_private_key_path: Option<PathBuf>,
+    pub jwt_public_key_path: Option<PathBuf>,
+    #[serde(default)]
+    pub jwt_previous_public_key_paths: Vec<PathBuf>,
+    /// Derives the salts reported for unknown users, `jwt_secret` if unset.
+    pub decoy_salt_secret: Option<String>,
+    #[serde(default = "default_totp_issuer")]
+    pub totp_issuer: String,
+    /// Parents and administrators act as a child profile until they enabled
+    /// TOTP. They can still enroll.
+    #[serde(default = "default_require_second_factor")]
+    pub require_second_factor: bool,
+}
+
+fn default_totp_issuer() -> String {
+    "wordy".into()
+}
+
+fn default_require_second_factor() -> bool {
+    true
+}
+
+impl AuthConfig {
+    pub fn refresh_secret(&self) -> &str {
+        self.jwt_refresh_secret.as_deref().unwrap_or_default()
+    }
+
+    pub fn decoy_salt_secret(&self) -> &str {
+        self.decoy_salt_secret
+            .as_deref()
+            .or(self.jwt_secret.as_deref())
+            .unwrap_or_default()
+    }
+}
+
+/// Limits of the failed login throttle.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct LoginConfig {
+    pub free_attempts: u32,
+    pub backoff_base_secs: u64,
+    pub lockout_max_secs: u64,
+    pub failure_window_secs: u64,
+}
+
+impl Default for LoginConfig {
+    fn default() -> Self {
+        Self {
+            free_attempts: 5,
+            backoff_base_secs: 1,
+            lockout_max_secs: 15 * 60,
+            failure_window_secs: 15 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct StorageConfig {
+    pub user_db_path: PathBuf,
+    pub token_db_path: PathBuf,
+    pub api_key_db_path: PathBuf,
+    pub audit_log_path: PathBuf,
+    pub oidc_link_db_path: PathBuf,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            user_db_path: "data/users.json".into(),
+            token_db_path: "data/tokens.json".into(),
+            api_key_db_path: "data/api_keys.json".into(),
+            audit_log_path: "data/audit.jsonl".into(),
+            oidc_link_db_path: "data/oidc_links.json".into(),
+        }
+    }
+}
+
+/// Single sign-on, disabled unless `issuer` is set.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct OidcConfig {
+    pub issuer: Option<String>,
+    /// Defaults to `<issuer>/.well-known/openid-configuration`.
+    pub discovery_url: Option<String>,
+    /// Overrides the `jwks_uri` of the discovery document.
+    pub jwks_url: Option<String>,
+    pub client_id: Option<String>,
+    /// Only for confidential clients, public clients rely on PKCE alone.
+    pub client_secret: Option<String>,
+    /// The callback route of this server as registered at the provider.
+    pub redirect_uri: Option<String>,
+    pub scopes: String,
+    /// Creates a local user for unknown subjects instead of rejecting them.
+    pub auto_create: bool,
+    pub default_role: Role,
+    /// Where the browser is sent after login, with the tokens in the
+    /// fragment. Without it the callback answers with the tokens as JSON.
+    pub post_login_redirect: Option<String>,
+}
+
+impl Default for OidcConfig {
+    fn default() -> Self {
+        Self {
+            issuer: None,
+            discovery_url: None,
+            jwks_url: None,
+            client_id: None,
+            client_secret: None,
+            redirect_uri: None,
+            scopes: "openid profile email".into(),
+            auto_create: false,
+            default_role: Role::Parent,
+            post_login_redirect: None,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OpenAiConfig {
+    pub api_key: Option<String>,
+    #[serde(default = "default_openai_model")]
+    pub model: String,
+    /// Instructions for the chat model, followed by one worked example.
+    #[serde(default = "default_system_prompt")]
+    pub system_prompt: String,
+}
+
+fn default_openai_model() -> String {
+    "gpt-3.5-turbo".into()
+}
+
+fn default_system_prompt() -> String {
+    "Suppose you are a kindergarten English starter teacher, I am going to ask you some simple \
+     words and ask you to say his English and give as much English explanation and example \
+     sentences as possible，and mark the English portion with ``, such as `foo`."
+        .into()
+}
+
+/// Speech synthesis. `voice`, `rate`, `pitch` and `style` are SSML values.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AzureTtsConfig {
+    pub key: Option<String>,
+    pub region: Option<String>,
+    #[serde(default = "default_voice")]
+    pub voice: String,
+    #[serde(default = "default_rate")]
+    pub rate: String,
+    #[serde(default = "default_pitch")]
+    pub pitch: String,
+    #[serde(default = "default_style")]
+    pub style: String,
+}
+
+fn default_voice() -> String {
+    "en-US-AriaNeural".into()
+}
+
+fn default_rate() -> String {
+    "-20%".into()
+}
+
+fn default_pitch() -> String {
+    "medium".into()
+}
+
+fn default_style() -> String {
+    "cheerful".into()
+}
+
+/// The administrator imported into an empty user store, formerly the single
+/// `CLIENT_*` user. Skipped unless `name` is set.
+#[derive(Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct SeedUserConfig {
+    pub name: Option<String>,
+    pub id: Option<u64>,
+    /// Client-side digest of the password.
+    pub password: Option<String>,
+    pub client_salt: Option<String>,
+    pub server_salt: Option<String>,
+}
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Str,
+    Bool,
+    Int,
+    /// Comma separated strings.
+    List,
+}
+
+/// A setting that can be given through the environment or a flag.
+struct Setting {
+    path: &'static str,
+    env: &'static str,
+    kind: Kind,
+}
+
+macro_rules! settings {
+    ($($path:literal $env:literal $kind:ident,)*) => {
+        &[$(Setting { path: $path, env: $env, kind: Kind::$kind },)*]
+    };
+}
+
+/// Environment variable names predate the config file and are kept as they
+/// were.
+const SETTINGS: &[Setting] = settings![
+    "server.bind" "BIND_ADDR" List,
+    "server.unix_socket" "UNIX_SOCKET_PATH" Str,
+    "server.assets_dir" "ASSETS_DIR" Str,
+    "server.audio_cache_dir" "AUDIO_CACHE_DIR" Str,
+    "server.shutdown_timeout_secs" "SHUTDOWN_TIMEOUT_SECS" Int,
+    "server.dispatch_queue" "DISPATCH_QUEUE_SIZE" Int,
+    "server.dispatch_user_queue" "DISPATCH_USER_QUEUE_SIZE" Int,
+    "server.dispatch_concurrency" "DISPATCH_CONCURRENCY" Int,
+    "server.dispatch_user_concurrency" "DISPATCH_USER_CONCURRENCY" Int,
+    "server.socket_queue" "SOCKET_QUEUE_SIZE" Int,
+    "server.socket_overflow" "SOCKET_OVERFLOW" Str,
+    "tls.cert_path" "TLS_CERT_PATH" Str,
+    "tls.key_path" "TLS_KEY_PATH" Str,
+    "tls.reload_interval_secs" "TLS_RELOAD_INTERVAL_SECS" Int,
+    "server.registration_enabled" "REGISTRATION_ENABLED" Bool,
+    "server.ws_query_token" "WS_QUERY_TOKEN" Bool,
+    "server.cors_origins" "CORS_ORIGINS" List,
+    "auth.jwt_algorithm" "JWT_ALGORITHM" Str,
+    "auth.jwt_secret" "JWT_SECRET" Str,
+    "auth.jwt_previous_secrets" "JWT_PREVIOUS_SECRETS" List,
+    "auth.jwt_refresh_secret" "JWT_REFRESH_SECRET" Str,
+    "auth.jwt_previous_refresh_secrets" "JWT_PREVIOUS_REFRESH_SECRETS" List,
+    "auth.jwt_private_key_path" "JWT_PRIVATE_KEY_PATH" Str,
+    "auth.jwt_public_key_path" "JWT_PUBLIC_KEY_PATH" Str,
+    "auth.jwt_previous_public_key_paths" "JWT_PREVIOUS_PUBLIC_KEY_PATHS" List,
+    "auth.decoy_salt_secret" "DECOY_SALT_SECRET" Str,
+    "auth.totp_issuer" "TOTP_ISSUER" Str,
+    "auth.require_second_factor" "REQUIRE_SECOND_FACTOR" Bool,
+    "login.free_attempts" "LOGIN_FREE_ATTEMPTS" Int,
+    "login.backoff_base_secs" "LOGIN_BACKOFF_BASE_SECS" Int,
+    "login.lockout_max_secs" "LOGIN_LOCKOUT_MAX_SECS" Int,
+    "login.failure_window_secs" "LOGIN_FAILURE_WINDOW_SECS" Int,
+    "storage.user_db_path" "USER_DB_PATH" Str,
+    "storage.token_db_path" "TOKEN_DB_PATH" Str,
+    "storage.api_key_db_path" "API_KEY_DB_PATH" Str,
+    "storage.audit_log_path" "AUDIT_LOG_PATH" Str,
+    "storage.oidc_link_db_path" "OIDC_LINK_DB_PATH" Str,
+    "oidc.issuer" "OIDC_ISSUER" Str,
+    "oidc.discovery_url" "OIDC_DISCOVERY_URL" Str,
+    "oidc.jwks_url" "OIDC_JWKS_URL" Str,
+    "oidc.client_id" "OIDC_CLIENT_ID" Str,
+    "oidc.client_secret" "OIDC_CLIENT_SECRET" Str,
+    "oidc.redirect_uri" "OIDC_REDIRECT_URI" Str,
+    "oidc.scopes" "OIDC_SCOPES" Str,
+    "oidc.auto_create" "OIDC_AUTO_CREATE" Bool,
+    "oidc.default_role" "OIDC_DEFAULT_ROLE" Str,
+    "oidc.post_login_redirect" "OIDC_POST_LOGIN_REDIRECT" Str,
+    "openai.api_key" "OPENAI_API_KEY" Str,
+    "openai.model" "OPENAI_MODEL" Str,
+    "openai.system_prompt" "OPENAI_SYSTEM_PROMPT" Str,
+    "azure_tts.key" "AZURE_TTS_KEY" Str,
+    "azure_tts.region" "AZURE_TTS_REGION" Str,
+    "azure_tts.voice" "AZURE_TTS_VOICE" Str,
+    "azure_tts.rate" "AZURE_TTS_RATE" Str,
+    "azure_tts.pitch" "AZURE_TTS_PITCH" Str,
+    "azure_tts.style" "AZURE_TTS_STYLE" Str,
+    "seed_user.name" "CLIENT_NAME" Str,
+    "seed_user.id" "CLIENT_ID" Int,
+    "seed_user.password" "CLIENT_PASSWORD" Str,
+    "seed_user.client_salt" "CLIENT_PASSWORD_SALT" Str,
+    "seed_user.server_salt" "SERVER_PASSWORD_SALT" Str,
+];
+
+fn setting(path: &str) -> Option<&'static Setting> {
+    SETTINGS.iter().find(|s| s.path == path)
+}
+
+fn parse_value(kind: Kind, raw: &str) -> Result<toml::Value> {
+    Ok(match kind {
+        Kind::Str => toml::Value::String(raw.to_owned()),
+        Kind::Bool => match raw {
+            "true" | "1" => toml::Value::Boolean(true),
+            "false" | "0" => toml::Value::Boolean(false),
+            _ => bail!("expected true or false, got {raw:?}"),
+        },
+        Kind::Int => toml::Value::Integer(
+            raw.parse()
+                .map_err(|_| anyhow!("expected an integer, got {raw:?}"))?,
+        ),
+        Kind::List => toml::Value::Array(
+            raw.split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(|s| toml::Value::String(s.to_owned()))
+                .collect(),
+        ),
+    })
+}
+
+/// Sets the dotted `path` in `table`, creating sections on the way.
+fn set_path(table: &mut toml::Table, path: &str, value: toml::Value) -> Result<()> {
+    let (sections, key) = path.rsplit_once('.').unwrap_or(("", path));
+    let mut table = table;
+    for section in sections.split('.').filter(|s| !s.is_empty()) {
+        table = table
+            .entry(section)
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+            .as_table_mut()
+            .ok_or_else(|| anyhow!("{section} is not a section"))?;
+    }
+    table.insert(key.to_owned(), value);
+    Ok(())
+}
+
+/// Command line: `--config <path>` plus any setting as `--section.key value`
+/// or `--section.key=value`.
+struct Args {
+    config: Option<PathBuf>,
+    settings: Vec<(String, String)>,
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Args> {
+    let mut args = args.into_iter();
+    let mut parsed = Args {
+        config: None,
+        settings: Vec::new(),
+    };
+    while let Some(arg) = args.next() {
+        let Some(flag) = arg.strip_prefix("--") else {
+            bail!("unexpected argument {arg:?}, settings are passed as --section.key value");
+        };
+        let (name, value) = match flag.split_once('=') {
+            Some((name, value)) => (name.to_owned(), value.to_owned()),
+            None => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| anyhow!("--{flag} needs a value"))?;
+                (flag.to_owned(), value)
+            }
+        };
+        if name == "config" {
+            parsed.config = Some(value.into());
+        } else if setting(&name).is_none() {
+            bail!("unknown flag --{name}");
+        } else {
+            parsed.settings.push((name, value));
+        }
+    }
+    Ok(parsed)
+}
+
+impl Config {
+    fn load_from(
+        args: impl IntoIterator<Item = String>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self> {
+        Self::from_table(merged_table(args, env)?)
+    }
+
+    fn from_table(table: toml::Table) -> Result<Self> {
+        let mut config: Self = toml::Value::Table(table.clone())
+            .try_into()
+            .context("invalid configuration")?;
+        config.validate()?;
+        config.source = table;
+        Ok(config)
+    }
+
+    /// Checks what serde cannot: settings that are required together or
+    /// only in some modes.
+    fn validate(&self) -> Result<()> {
+        if self.server.bind.is_empty() && self.server.unix_socket.is_none() {
+            bail!("nothing to listen on: set server.bind or server.unix_socket");
+        }
+        let server = &self.server;
+        for (value, path) in [
+            (server.dispatch_queue, "server.dispatch_queue"),
+            (server.dispatch_user_queue, "server.dispatch_user_queue"),
+            (server.dispatch_concurrency, "server.dispatch_concurrency"),
+            (server.dispatch_user_concurrency, "server.dispatch_user_concurrency"),
+            (server.socket_queue, "server.socket_queue"),
+        ] {
+            if value == 0 {
+                bail!("{path} must be at least 1");
+            }
+        }
+        let tls = &self.tls;
+        require(tls.key_path.is_some() || tls.cert_path.is_none(), "tls.key_path")?;
+        require(tls.cert_path.is_some() || tls.key_path.is_none(), "tls.cert_path")?;
+        let auth = &self.auth;
+        require(auth.jwt_refresh_secret.is_some(), "auth.jwt_refresh_secret")?;
+        match auth.jwt_algorithm {
+            JwtAlgorithm::HS256 => require(auth.jwt_secret.is_some(), "auth.jwt_secret")?,
+            JwtAlgorithm::EdDSA | JwtAlgorithm::RS256 => {
+                require(auth.jwt_private_key_path.is_some(), "auth.jwt_private_key_path")?;
+                require(auth.jwt_public_key_path.is_some(), "auth.jwt_public_key_path")?;
+            }
+        }
+        require(
+            auth.decoy_salt_secret.is_some() || auth.jwt_secret.is_some(),
+            "auth.decoy_salt_secret",
+        )?;
+        if self.oidc.issuer.is_some() {
+            require(self.oidc.client_id.is_some(), "oidc.client_id")?;
+            require(self.oidc.redirect_uri.is_some(), "oidc.redirect_uri")?;
+        }
+        require(self.openai.api_key.is_some(), "openai.api_key")?;
+        require(self.azure_tts.key.is_some(), "azure_tts.key")?;
+        require(self.azure_tts.region.is_some(), "azure_tts.region")?;
+        let seed = &self.seed_user;
+        if seed.name.is_some() {
+            require(seed.id.is_some(), "seed_user.id")?;
+            require(seed.password.is_some(), "seed_user.password")?;
+            require(seed.client_salt.is_some(), "seed_user.client_salt")?;
+            require(seed.server_salt.is_some(), "seed_user.server_salt")?;
+        }
+        Ok(())
+    }
+}
+
+/// The file, env and flag layers merged into one table, before any checks.
+fn merged_table(
+    args: impl IntoIterator<Item = String>,
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<toml::Table> {
+    let args = parse_args(args)?;
+    let path = args.config.clone().or_else(|| {
+        env("CONFIG_PATH")
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from)
+    });
+    let mut table = match &path {
+        Some(path) => read_table(path)?,
+        None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+            read_table(Path::new(DEFAULT_CONFIG_PATH))?
+        }
+        None => toml::Table::new(),
+    };
+    for setting in SETTINGS {
+        let Some(raw) = env(setting.env).filter(|v| !v.is_empty()) else {
+            continue;
+        };
+        let value = parse_value(setting.kind, &raw).with_context(|| setting.env)?;
+        set_path(&mut table, setting.path, value)?;
+    }
+    for (path, raw) in &args.settings {
+        let kind = setting(path).map_or(Kind::Str, |s| s.kind);
+        let value = parse_value(kind, raw).with_context(|| format!("--{path}"))?;
+        set_path(&mut table, path, value)?;
+    }
+    for section in ["auth", "openai", "azure_tts"] {
+        table
+            .entry(section)
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+    }
+    Ok(table)
+}
+
+fn read_table(path: &Path) -> Result<toml::Table> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("read config file {}", path.display()))?;
+    toml::from_str(&text).with_context(|| format!("parse config file {}", path.display()))
+}
+
+/// Fails with every way `path` can be set when `present` is false.
+fn require(present: bool, path: &str) -> Result<()> {
+    if present {
+        return Ok(());
+    }
+    let env = setting(path).map_or(String::new(), |s| format!(", {}", s.env));
+    Err(anyhow!(
+        "missing setting {path}: set it in the config file{env} or with --{path}"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn load(args: &[&str], env: &[(&str, &str)]) -> Result<Config> {
+        let env = env
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<HashMap<_, _>>();
+        Config::load_from(args.iter().map(|a| a.to_string()), |k| env.get(k).cloned())
+    }
+
+    const REQUIRED: &[(&str, &str)] = &[
+        ("JWT_SECRET", "a"),
+        ("JWT_REFRESH_SECRET", "b"),
+        ("OPENAI_API_KEY", "k"),
+        ("AZURE_TTS_KEY", "k"),
+        ("AZURE_TTS_REGION", "r"),
+    ];
+
+    #[test]
+    fn test_flags_override_env_override_file() {
+        let dir = std::env::temp_dir().join(format!("config-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        std::fs::write(
+            &path,
+            "[server]\nbind = [\"127.0.0.1:1\"]\nregistration_enabled = true\n\
+             [login]\nfree_attempts = 1\nlockout_max_secs = 7\n",
+        )
+        .unwrap();
+        let path = path.to_str().unwrap();
+        let env = [REQUIRED, &[("LOGIN_FREE_ATTEMPTS", "2"), ("BIND_ADDR", "127.0.0.1:2")]].concat();
+        let config = load(&["--config", path, "--server.bind=127.0.0.1:3"], &env).unwrap();
+        assert_eq!(config.server.bind, [SocketAddr::from(([127, 0, 0, 1], 3))]);
+        assert!(config.server.registration_enabled);
+        assert_eq!(config.login.free_attempts, 2);
+        assert_eq!(config.login.lockout_max_secs, 7);
+        assert_eq!(config.login.failure_window_secs, 15 * 60);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_errors_name_the_setting() {
+        let err = load(&[], &REQUIRED[1..]).err().unwrap().to_string();
+        assert!(err.contains("auth.jwt_secret") && err.contains("JWT_SECRET"), "{err}");
+        let env = [REQUIRED, &[("LOGIN_FREE_ATTEMPTS", "many")]].concat();
+        let err = format!("{:#}", load(&[], &env).err().unwrap());
+        assert!(err.contains("LOGIN_FREE_ATTEMPTS"), "{err}");
+        assert!(load(&["--server.nope", "1"], REQUIRED).is_err());
+        let config = load(&[], &[REQUIRED, &[("JWT_PREVIOUS_SECRETS", "x, y")]].concat()).unwrap();
+        assert_eq!(config.auth.jwt_previous_secrets, ["x", "y"]);
+        assert!(load(&[], &[REQUIRED, &[("CONFIG_PATH", "")]].concat()).is_ok());
+    }
+}
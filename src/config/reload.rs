@@ -0,0 +1,299 @@
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use serde::Serialize;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
+use tracing::{info, warn};
+
+use super::{merged_table, set_path, Config};
+use crate::{
+    state::AppState,
+    utils::audit::{AuditEvent, AuditKind, Outcome},
+};
+
+/// Settings that take effect without a restart. A trailing dot covers a whole
+/// section. Listeners, storage, keys and other secrets are only read at
+/// startup.
+const RELOADABLE: &[&str] = &[
+    "server.registration_enabled",
+    "server.ws_query_token",
+    "server.cors_origins",
+    "server.dispatch_user_queue",
+    "server.dispatch_concurrency",
+    "server.dispatch_user_concurrency",
+    "server.socket_queue",
+    "server.socket_overflow",
+    "auth.totp_issuer",
+    "auth.require_second_factor",
+    "login.",
+    "openai.model",
+    "openai.system_prompt",
+    "azure_tts.voice",
+    "azure_tts.rate",
+    "azure_tts.pitch",
+    "azure_tts.style",
+];
+
+fn is_reloadable(path: &str) -> bool {
+    RELOADABLE.iter().any(|p| match p.strip_suffix('.') {
+        Some(section) => path.strip_prefix(section).is_some_and(|k| k.starts_with('.')),
+        None => path == *p,
+    })
+}
+
+/// Dotted paths of every value that differs between `old` and `new`.
+fn diff(old: &toml::Table, new: &toml::Table, prefix: &str, changed: &mut Vec<String>) {
+    let empty = toml::Table::new();
+    let keys = old.keys().chain(new.keys()).collect::<BTreeSet<_>>();
+    for key in keys {
+        let path = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{prefix}.{key}")
+        };
+        match (old.get(key), new.get(key)) {
+            (old, new) if old == new => {}
+            (Some(toml::Value::Table(old)), Some(toml::Value::Table(new))) => {
+                diff(old, new, &path, changed)
+            }
+            (Some(toml::Value::Table(old)), None) => diff(old, &empty, &path, changed),
+            (None, Some(toml::Value::Table(new))) => diff(&empty, new, &path, changed),
+            _ => changed.push(path),
+        }
+    }
+}
+
+fn get_path<'a>(table: &'a toml::Table, path: &str) -> Option<&'a toml::Value> {
+    let (sections, key) = path.rsplit_once('.').unwrap_or(("", path));
+    let mut table = table;
+    for section in sections.split('.').filter(|s| !s.is_empty()) {
+        table = table.get(section)?.as_table()?;
+    }
+    table.get(key)
+}
+
+fn remove_path(table: &mut toml::Table, path: &str) {
+    let (sections, key) = path.rsplit_once('.').unwrap_or(("", path));
+    let mut table = table;
+    for section in sections.split('.').filter(|s| !s.is_empty()) {
+        match table.get_mut(section).and_then(toml::Value::as_table_mut) {
+            Some(inner) => table = inner,
+            None => return,
+        }
+    }
+    table.remove(key);
+}
+
+/// Outcome of one reload. Only setting names are reported, never values.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReloadAttempt {
+    pub time: i64,
+    /// What asked for the reload, `sighup` or `api`.
+    pub trigger: String,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Changed settings that are now in effect.
+    pub applied: Vec<String>,
+    /// Changed settings that are ignored until the next restart.
+    pub restart_required: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReloadStatus {
+    /// When the configuration in effect was loaded.
+    pub loaded_at: i64,
+    /// Counts the reloads that changed something.
+    pub generation: u64,
+    pub last_attempt: Option<ReloadAttempt>,
+}
+
+type Env = Box<dyn Fn(&str) -> Option<String> + Send + Sync>;
+
+/// The configuration in effect. Readers take a snapshot with
+/// [`SharedConfig::get`], so a reload never changes a value halfway through
+/// a request. Services that cache settings follow
+/// [`SharedConfig::subscribe`].
+pub struct SharedConfig {
+    args: Vec<String>,
+    env: Env,
+    current: watch::Sender<Arc<Config>>,
+    /// Also serializes reloads.
+    status: Mutex<ReloadStatus>,
+}
+
+impl SharedConfig {
+    /// Loads the configuration of this process, see [`Config`].
+    pub fn load() -> Result<Self> {
+        Self::with_env(
+            std::env::args().skip(1).collect(),
+            Box::new(|key| std::env::var(key).ok()),
+        )
+    }
+
+    pub(crate) fn with_env(args: Vec<String>, env: Env) -> Result<Self> {
+        let config = Config::load_from(args.clone(), &env)?;
+        Ok(Self {
+            args,
+            env,
+            current: watch::channel(Arc::new(config)).0,
+            status: Mutex::new(ReloadStatus {
+                loaded_at: chrono::Utc::now().timestamp(),
+                generation: 0,
+                last_attempt: None,
+            }),
+        })
+    }
+
+    pub fn get(&self) -> Arc<Config> {
+        self.current.borrow().clone()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Arc<Config>> {
+        self.current.subscribe()
+    }
+
+    pub fn status(&self) -> ReloadStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Reads every layer again and swaps in the changed reloadable settings.
+    /// An invalid configuration is rejected as a whole and the current one
+    /// stays in effect.
+    pub fn reload(&self, trigger: &str) -> ReloadAttempt {
+        let mut status = self.status.lock().unwrap();
+        let time = chrono::Utc::now().timestamp();
+        let attempt = match self.apply_changes() {
+            Ok((applied, restart_required)) => {
+                info!(
+                    "config reloaded by {}: applied {:?}, restart required for {:?}",
+                    trigger, applied, restart_required
+                );
+                if !applied.is_empty() {
+                    status.loaded_at = time;
+                    status.generation += 1;
+                }
+                ReloadAttempt {
+                    time,
+                    trigger: trigger.to_owned(),
+                    ok: true,
+                    error: None,
+                    applied,
+                    restart_required,
+                }
+            }
+            Err(e) => {
+                warn!("config reload by {} failed: {:#}", trigger, e);
+                ReloadAttempt {
+                    time,
+                    trigger: trigger.to_owned(),
+                    ok: false,
+                    error: Some(format!("{e:#}")),
+                    applied: Vec::new(),
+                    restart_required: Vec::new(),
+                }
+            }
+        };
+        status.last_attempt = Some(attempt.clone());
+        attempt
+    }
+
+    fn apply_changes(&self) -> Result<(Vec<String>, Vec<String>)> {
+        let fresh = merged_table(self.args.clone(), &self.env)?;
+        // Rejects a broken file even if only restart-only settings changed.
+        Config::from_table(fresh.clone())?;
+        let current = self.get();
+        let mut changed = Vec::new();
+        diff(&current.source, &fresh, "", &mut changed);
+        let (applied, restart_required): (Vec<_>, Vec<_>) =
+            changed.into_iter().partition(|path| is_reloadable(path));
+        if !applied.is_empty() {
+            let mut table = current.source.clone();
+            for path in &applied {
+                match get_path(&fresh, path) {
+                    Some(value) => set_path(&mut table, path, value.clone())?,
+                    None => remove_path(&mut table, path),
+                }
+            }
+            self.current.send_replace(Arc::new(Config::from_table(table)?));
+        }
+        Ok((applied, restart_required))
+    }
+}
+
+impl ReloadAttempt {
+    pub fn outcome(&self) -> Outcome {
+        if self.ok {
+            Outcome::Success
+        } else {
+            Outcome::Failure
+        }
+    }
+
+    /// Audit record of the attempt, without an actor.
+    pub fn audit_event(&self) -> AuditEvent {
+        let event = AuditEvent::new(AuditKind::ConfigReloaded, self.outcome());
+        match &self.error {
+            Some(error) => event.detail(error.as_str()),
+            None => event.detail(format!("applied {:?}", self.applied)),
+        }
+    }
+}
+
+/// Reloads the configuration of `app` on every SIGHUP.
+pub fn reload_on_sighup(app: Arc<AppState>) -> Result<()> {
+    let mut hangups = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            app.config.reload("sighup").audit_event().record(&app.audit);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reload_applies_only_reloadable_settings() {
+        let dir = std::env::temp_dir().join(format!("reload-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        let write = |extra: &str| {
+            let base = "[auth]\njwt_secret = \"a\"\njwt_refresh_secret = \"b\"\n\
+                        [openai]\napi_key = \"k\"\n[azure_tts]\nkey = \"k\"\nregion = \"r\"\n";
+            std::fs::write(&path, format!("{base}{extra}")).unwrap();
+        };
+        write("");
+        let args = vec!["--config".into(), path.to_str().unwrap().into()];
+        let shared = SharedConfig::with_env(args, Box::new(|_| None)).unwrap();
+        let updates = shared.subscribe();
+
+        write("[login]\nfree_attempts = 9\n[storage]\nuser_db_path = \"x.json\"\n");
+        let attempt = shared.reload("test");
+        assert!(attempt.ok, "{:?}", attempt.error);
+        assert_eq!(attempt.applied, ["login.free_attempts"]);
+        assert_eq!(attempt.restart_required, ["storage.user_db_path"]);
+        assert!(updates.has_changed().unwrap());
+        let config = shared.get();
+        assert_eq!(config.login.free_attempts, 9);
+        assert_eq!(config.storage.user_db_path, std::path::Path::new("data/users.json"));
+
+        write("[login]\nfree_attempts = \"many\"\n");
+        assert!(!shared.reload("test").ok);
+        assert_eq!(shared.get().login.free_attempts, 9);
+        assert_eq!(shared.status().generation, 1);
+
+        write("");
+        assert_eq!(shared.reload("test").applied, ["login.free_attempts"]);
+        assert_eq!(shared.get().login.free_attempts, 5);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,55 @@
+use anyhow::Result;
+use axum::async_trait;
+use serde_json::json;
+
+use super::{mismatched, Context, Schema, SystemHandler};
+use crate::{
+    auth::Role,
+    utils::{
+        event::{Event, EventType},
+        openai::en_teacher_chat,
+    },
+};
+
+/// Explains a word or sentence like an English teacher, with ChatGPT.
+pub struct Chat;
+
+#[async_trait]
+impl SystemHandler for Chat {
+    fn event_type(&self) -> EventType {
+        EventType::Chat
+    }
+
+    fn response_type(&self) -> EventType {
+        EventType::Chat
+    }
+
+    fn required_role(&self) -> Role {
+        Role::Child
+    }
+
+    fn schema(&self) -> Schema {
+        Schema {
+            request: json!({
+                "type": "string",
+                "description": "Word or sentence to explain",
+            }),
+            response: json!({
+                "type": "string",
+                "description": "Explanation with example sentences in backticks",
+            }),
+        }
+    }
+
+    async fn handle(&self, ctx: &Context<'_>, event: &Event) -> Result<Event> {
+        let Event::Chat(message) = event else {
+            return Err(mismatched(EventType::Chat));
+        };
+        let text = en_teacher_chat(&ctx.config.openai, message).await?;
+        let res = text
+            .choices
+            .first()
+            .and_then(|choice| choice.message.content.clone());
+        Ok(Event::Chat(res.unwrap_or_default()))
+    }
+}
@@ -0,0 +1,110 @@
+//! Requests addressed to the server (`to: 0`). Each event type is answered by
+//! one [`SystemHandler`], looked up in the [`Registry`].
+
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::Result;
+use axum::async_trait;
+use serde::Serialize;
+
+use crate::{
+    auth::Role,
+    config::Config,
+    utils::event::{ErrorCode, ErrorDetail, Event, EventType},
+};
+
+mod chat;
+mod speech;
+
+pub use chat::Chat;
+pub use speech::Speech;
+
+/// Payloads of a request and its response, as JSON Schema.
+#[derive(Debug, Clone, Serialize)]
+pub struct Schema {
+    pub request: serde_json::Value,
+    pub response: serde_json::Value,
+}
+
+/// What a handler gets besides the event.
+pub struct Context<'a> {
+    pub config: &'a Config,
+    /// Authenticated sender of the request.
+    pub uid: u64,
+}
+
+#[async_trait]
+pub trait SystemHandler: Send + Sync {
+    /// Event type of the requests it answers.
+    fn event_type(&self) -> EventType;
+    /// Event type of its responses.
+    fn response_type(&self) -> EventType;
+    /// Least privileged role allowed to send the request.
+    fn required_role(&self) -> Role;
+    fn schema(&self) -> Schema;
+    /// Answers `event`, which is of [`SystemHandler::event_type`] but not
+    /// necessarily of the matching [`Event`] variant.
+    async fn handle(&self, ctx: &Context<'_>, event: &Event) -> Result<Event>;
+}
+
+/// Error for an event whose payload does not match its event type.
+pub fn mismatched(expected: EventType) -> anyhow::Error {
+    let message = format!("expected a {expected:?} event");
+    ErrorDetail::new(ErrorCode::BadRequest, message).into()
+}
+
+/// Handlers by the event type they answer. [`EventType`] is a closed enum and
+/// a message naming an unknown type fails to parse before it gets here, so a
+/// new kind of request needs its [`Event`] and [`EventType`] variants as well
+/// as a handler. Registering alone only picks which handler answers.
+#[derive(Default)]
+pub struct Registry {
+    handlers: HashMap<EventType, Arc<dyn SystemHandler>>,
+}
+
+impl Registry {
+    /// The handlers shipped with the server.
+    pub fn builtin() -> Self {
+        let mut registry = Self::default();
+        registry.register(Chat);
+        registry.register(Speech);
+        registry
+    }
+
+    /// Panics if the event type already has a handler.
+    pub fn register(&mut self, handler: impl SystemHandler + 'static) {
+        let event_type = handler.event_type();
+        let previous = self.handlers.insert(event_type.clone(), Arc::new(handler));
+        assert!(previous.is_none(), "two handlers for {event_type:?}");
+    }
+
+    pub fn get(&self, event_type: &EventType) -> Option<&dyn SystemHandler> {
+        self.handlers.get(event_type).map(|handler| handler.as_ref())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &dyn SystemHandler> {
+        self.handlers.values().map(|handler| handler.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_handlers() {
+        let registry = Registry::builtin();
+        for event_type in [EventType::Chat, EventType::Speech] {
+            let handler = registry.get(&event_type).unwrap();
+            assert_eq!(handler.event_type(), event_type);
+            assert_eq!(handler.required_role(), Role::Child);
+        }
+        assert!(registry.get(&EventType::Loading).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "two handlers for Chat")]
+    fn test_register_rejects_duplicates() {
+        Registry::builtin().register(Chat);
+    }
+}
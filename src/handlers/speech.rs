@@ -0,0 +1,53 @@
+use anyhow::Result;
+use axum::async_trait;
+use serde_json::json;
+
+use super::{mismatched, Context, Schema, SystemHandler};
+use crate::{
+    auth::Role,
+    utils::{
+        azure_tts::fetch_speed,
+        event::{Event, EventType},
+    },
+};
+
+/// Reads text aloud with Azure TTS. Audio is cached, so repeated text costs
+/// nothing.
+pub struct Speech;
+
+#[async_trait]
+impl SystemHandler for Speech {
+    fn event_type(&self) -> EventType {
+        EventType::Speech
+    }
+
+    fn response_type(&self) -> EventType {
+        EventType::Speech
+    }
+
+    fn required_role(&self) -> Role {
+        Role::Child
+    }
+
+    fn schema(&self) -> Schema {
+        Schema {
+            request: json!({
+                "type": "string",
+                "description": "English text to read aloud",
+            }),
+            response: json!({
+                "type": "string",
+                "description": "File name of the audio, served from the server root",
+            }),
+        }
+    }
+
+    async fn handle(&self, ctx: &Context<'_>, event: &Event) -> Result<Event> {
+        let Event::Speech(message) = event else {
+            return Err(mismatched(EventType::Speech));
+        };
+        let cache_dir = ctx.config.server.audio_cache_dir();
+        let path = fetch_speed(&ctx.config.azure_tts, cache_dir, message).await?;
+        Ok(Event::Speech(path))
+    }
+}
@@ -1,4 +1,9 @@
+pub mod api;
 pub mod auth;
 pub mod channel;
+pub mod config;
+pub mod handlers;
+pub mod server;
+pub mod state;
 pub mod utils;
 pub mod ws;
\ No newline at end of file
@@ -1,30 +1,29 @@
-use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+use std::{sync::Arc, time::Duration};
 
 use axum::{
-    extract::{Path, Query},
-    http::StatusCode,
-    response::IntoResponse,
-    routing::{get, post},
-    Json, Router,
+    body::Body,
+    http::{header, Request},
+    routing::get,
+    Router,
 };
-use rand::{distributions::Alphanumeric, thread_rng, Rng};
-use serde_json::Value;
-use tokio::{sync::mpsc, net::TcpListener};
+use tokio::sync::mpsc;
 use tower_http::{
-    cors::{Any, CorsLayer},
+    cors::{AllowOrigin, Any, CorsLayer},
+    sensitive_headers::SetSensitiveRequestHeadersLayer,
     services::ServeDir,
-    trace::{DefaultMakeSpan, TraceLayer},
+    trace::TraceLayer,
+};
+use chat_ws::{
+    api,
+    channel::handle_message,
+    config::{self, SharedConfig},
+    server::{self, shutdown::Shutdown},
+    state::AppState,
+    utils::{event, redact::redact_tokens},
+    ws,
 };
 use tracing::info;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-use utils::event;
-
-use crate::channel::handle_message;
-
-mod auth;
-mod channel;
-mod utils;
-mod ws;
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
@@ -32,118 +31,74 @@ async fn main() -> Result<(), anyhow::Error> {
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "chat_ws=debug,tower_http=debug".into()),
+                .unwrap_or_else(|_| "chat_ws=debug,tower_http=debug,audit=info".into()),
         )
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let assets_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("assets");
-    let (s, mut r) = mpsc::unbounded_channel::<event::ChannelMessage>();
-    let state = Arc::new(ws::state::WsState::new(s.clone()));
+    let config = Arc::new(SharedConfig::load()?);
+    let app_state = Arc::new(AppState::open(config.clone())?);
+    app_state.follow_reloads();
+    config::reload_on_sighup(app_state.clone())?;
+    let shutdown = Shutdown::new();
+    server::shutdown::stop_on_signal(shutdown.clone())?;
+
+    let (s, r) = mpsc::channel::<event::ChannelMessage>(config.get().server.dispatch_queue);
+    let state = Arc::new(ws::state::WsState::new(app_state.clone(), s.clone(), shutdown.clone()));
     let state1 = state.clone();
     tokio::spawn(async move {
-        handle_message(&mut r, state1).await;
+        handle_message(r, state1).await;
     });
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
-    info!("listening on {}", addr.to_string());
-
+    let origins = config.clone();
     let cors = CorsLayer::new()
         .allow_methods(Any)
-        .allow_origin(Any)
+        .allow_origin(AllowOrigin::predicate(move |origin, _| {
+            let allowed = &origins.get().server.cors_origins;
+            allowed.is_empty() || allowed.iter().any(|o| o.as_bytes() == origin.as_bytes())
+        }))
         .allow_headers(Any);
 
-    let app = Router::new()
-        .fallback_service(ServeDir::new(assets_dir).append_index_html_on_directories(true))
+    let startup = config.get();
+    let assets = ServeDir::new(&startup.server.assets_dir).append_index_html_on_directories(true);
+    let app = match &startup.server.audio_cache_dir {
+        Some(cache) => Router::new().fallback_service(assets.fallback(ServeDir::new(cache))),
+        None => Router::new().fallback_service(assets),
+    };
+    let app = app
         .nest("/ws", ws::router::router(state.clone()))
-        .route("/api/login", post(handle_login))
-        .route("/api/refresh_token", post(handle_refresh_token))
-        .route("/api/:user/salt", get(handle_client_salt))
-        .layer(cors)
-        .layer(
-            TraceLayer::new_for_http()
-                .make_span_with(DefaultMakeSpan::default().include_headers(true)),
-        );
-
-        let listener = TcpListener::bind(&addr).await?;
-
-    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
-        .await?;
-    anyhow::Ok(())
-}
-
-async fn handle_login(req: axum::Json<auth::LoginRequest>) -> impl IntoResponse {
-    info!("login request: {:?}", req);
-    let db_user = auth::Auth::new_by_name(req.name.to_owned());
-    if db_user.is_err() {
-        return (
-            StatusCode::UNAUTHORIZED,
-            "Unauthorized: invalid username or password",
+        .nest(
+            "/api",
+            api::router(api::ApiState {
+                app: app_state.clone(),
+                ws: state.clone(),
+            }),
         )
-            .into_response();
-    }
-    let resp = db_user.unwrap().login(&req.name, &req.password);
-    resp.map_or_else(
-        |_| {
-            (
-                StatusCode::UNAUTHORIZED,
-                "Unauthorized: invalid username or password",
+        .route(
+            "/.well-known/jwks.json",
+            get(api::handle_jwks).with_state(app_state.clone()),
+        )
+        .layer(cors)
+        .layer(TraceLayer::new_for_http().make_span_with(|req: &Request<Body>| {
+            // Same fields as `DefaultMakeSpan`, but with tokens removed from
+            // the uri. Credential headers are hidden by the layer below.
+            tracing::debug_span!(
+                "request",
+                method = %req.method(),
+                uri = %redact_tokens(&req.uri().to_string()),
+                version = ?req.version(),
+                headers = ?req.headers(),
             )
-                .into_response()
-        },
-        |resp| (StatusCode::OK, Json(resp)).into_response(),
-    )
-}
+        }))
+        .layer(SetSensitiveRequestHeadersLayer::new([
+            header::AUTHORIZATION,
+            header::COOKIE,
+            header::SEC_WEBSOCKET_PROTOCOL,
+        ]));
 
-#[derive(serde::Deserialize, Debug, serde::Serialize)]
-struct ClientSaltRequest {
-    salt: String,
-}
-
-async fn handle_client_salt(Path(name): Path<String>) -> Json<ClientSaltRequest> {
-    info!("client salt request: {:?}", name);
-    let salt = match auth::Auth::new_by_name(name) {
-        Ok(db_user) => db_user.client_salt,
-        Err(_) => {
-            let rand_string: String = thread_rng()
-                .sample_iter(&Alphanumeric)
-                .take(30)
-                .map(char::from)
-                .collect();
-            rand_string
-        }
-    };
-    Json(ClientSaltRequest { salt })
-}
-#[derive(serde::Deserialize, Debug, serde::Serialize)]
-struct RefreshTokenRequest {
-    refresh_token: String,
-}
-async fn handle_refresh_token(
-    Json(RefreshTokenRequest { refresh_token }): Json<RefreshTokenRequest>,
-) -> impl IntoResponse {
-    info!("refresh token request: {:?}", refresh_token);
-    let db_user = auth::Auth::from_refresh_token(&refresh_token);
-    db_user.map_or_else(
-        |_| {
-            return (
-                StatusCode::UNAUTHORIZED,
-                "Unauthorized: invalid refresh token",
-            )
-                .into_response();
-        },
-        |db_user| {
-            let result = db_user.refresh_access_token();
-            result.map_or_else(
-                |_| {
-                    (
-                        StatusCode::UNAUTHORIZED,
-                        "Unauthorized: invalid refresh token",
-                    )
-                        .into_response()
-                },
-                |resp| (StatusCode::OK, Json(resp)).into_response(),
-            )
-        },
-    )
+    server::serve(&startup, app, &shutdown).await?;
+    let timeout = Duration::from_secs(startup.server.shutdown_timeout_secs);
+    shutdown.drain(timeout, || state.all_uuids().len()).await;
+    info!("shut down");
+    anyhow::Ok(())
 }
@@ -0,0 +1,187 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use axum::{
+    extract::ConnectInfo,
+    http::{HeaderMap, Request},
+    Router,
+};
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto::Builder,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, UnixListener},
+    task::JoinSet,
+};
+use tokio_rustls::TlsAcceptor;
+use tower::ServiceExt;
+use tracing::{debug, error, info};
+
+use crate::config::Config;
+
+pub mod shutdown;
+pub mod tls;
+
+use shutdown::Shutdown;
+
+/// Who is on the other end of a connection.
+#[derive(Clone, Copy)]
+enum Peer {
+    Tcp(SocketAddr),
+    /// A reverse proxy on a Unix socket, the client is in its headers.
+    Proxy,
+}
+
+/// The client behind a proxy: the last `X-Forwarded-For` entry, which the
+/// proxy appended itself. Only trusted on the Unix socket, which nothing but
+/// local processes can reach.
+fn forwarded_addr(headers: &HeaderMap) -> SocketAddr {
+    let ip = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+        .next_back()
+        .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    SocketAddr::new(ip, 0)
+}
+
+/// Serves HTTP/1 with upgrades, for WebSockets, and HTTP/2 on `io`. Handlers
+/// see the client address as `ConnectInfo<SocketAddr>` whatever the listener.
+async fn serve_connection<I>(io: I, peer: Peer, app: Router)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = hyper::service::service_fn(move |mut req: Request<Incoming>| {
+        let addr = match peer {
+            Peer::Tcp(addr) => addr,
+            Peer::Proxy => forwarded_addr(req.headers()),
+        };
+        req.extensions_mut().insert(ConnectInfo(addr));
+        app.clone().oneshot(req)
+    });
+    if let Err(e) = Builder::new(TokioExecutor::new())
+        .serve_connection_with_upgrades(TokioIo::new(io), service)
+        .await
+    {
+        debug!("connection error: {}", e);
+    }
+}
+
+/// Like `axum::serve`, connection errors are skipped and anything else, such
+/// as running out of file descriptors, pauses accepting for a second.
+async fn accept_failed(e: io::Error) {
+    if !matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+    ) {
+        error!("accept error: {}", e);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+async fn serve_tcp(
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    app: Router,
+    shutdown: Shutdown,
+) {
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.stopping() => return,
+        };
+        let (stream, addr) = match accepted {
+            Ok(conn) => conn,
+            Err(e) => {
+                accept_failed(e).await;
+                continue;
+            }
+        };
+        let app = app.clone();
+        let tls = tls.clone();
+        tokio::spawn(async move {
+            match tls {
+                Some(tls) => match tls.accept(stream).await {
+                    Ok(stream) => serve_connection(stream, Peer::Tcp(addr), app).await,
+                    Err(e) => debug!("tls handshake with {} failed: {}", addr, e),
+                },
+                None => serve_connection(stream, Peer::Tcp(addr), app).await,
+            }
+        });
+    }
+}
+
+async fn serve_unix(listener: UnixListener, app: Router, shutdown: Shutdown) {
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.stopping() => return,
+        };
+        let stream = match accepted {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                accept_failed(e).await;
+                continue;
+            }
+        };
+        tokio::spawn(serve_connection(stream, Peer::Proxy, app.clone()));
+    }
+}
+
+/// Serves `app` on every listener of `[server]` until `shutdown` stops. All of
+/// them are bound before anything is served, so a taken port fails startup
+/// instead of leaving the server half up. Open connections are left to the
+/// caller.
+pub async fn serve(config: &Config, app: Router, shutdown: &Shutdown) -> Result<()> {
+    let tls = tls::acceptor(&config.tls)?;
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    let mut servers = JoinSet::new();
+    for addr in &config.server.bind {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("bind {addr}"))?;
+        info!("listening on {}://{}", scheme, addr);
+        let acceptor = tls.as_ref().map(|(acceptor, _)| acceptor.clone());
+        servers.spawn(serve_tcp(listener, acceptor, app.clone(), shutdown.clone()));
+    }
+    if let Some(path) = &config.server.unix_socket {
+        // A socket file left behind by an earlier run would make bind fail.
+        if path.exists() {
+            std::fs::remove_file(path).with_context(|| format!("remove {}", path.display()))?;
+        }
+        let listener =
+            UnixListener::bind(path).with_context(|| format!("bind {}", path.display()))?;
+        info!("listening on unix:{}", path.display());
+        servers.spawn(serve_unix(listener, app.clone(), shutdown.clone()));
+    }
+    while servers.join_next().await.is_some() {}
+    if let Some(path) = &config.server.unix_socket {
+        let _ = std::fs::remove_file(path);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forwarded_addr_takes_the_proxy_entry() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(forwarded_addr(&headers).ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+        headers.append("x-forwarded-for", "10.0.0.1, 10.0.0.2".parse().unwrap());
+        headers.append("x-forwarded-for", "192.0.2.7".parse().unwrap());
+        assert_eq!(forwarded_addr(&headers).ip(), "192.0.2.7".parse::<IpAddr>().unwrap());
+    }
+}
@@ -0,0 +1,109 @@
+use std::{future::Future, time::Duration};
+
+use anyhow::Result;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    task::JoinHandle,
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{info, warn};
+
+/// How long sockets get to flush their last events and close once the
+/// requests are drained.
+const CLOSE_GRACE: Duration = Duration::from_secs(5);
+
+/// Coordinates a graceful shutdown in two phases. Once `stopping`, the
+/// listeners close and no new request is started. Once `drained`, requests
+/// started before have finished, or ran out of time, and every socket tells
+/// its client the server is restarting and closes.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    stopping: CancellationToken,
+    drained: CancellationToken,
+    tasks: TaskTracker,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.is_cancelled()
+    }
+
+    pub async fn stopping(&self) {
+        self.stopping.cancelled().await
+    }
+
+    pub async fn drained(&self) {
+        self.drained.cancelled().await
+    }
+
+    /// Starts the shutdown, does nothing if it already started.
+    pub fn stop(&self) {
+        self.stopping.cancel();
+    }
+
+    /// Spawns request work that a shutdown waits for.
+    pub fn spawn<F>(&self, task: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.tasks.spawn(task)
+    }
+
+    /// Waits up to `timeout` for the tracked requests, then has the sockets
+    /// close and waits, briefly, until `open_sockets` reports none left.
+    pub async fn drain(&self, timeout: Duration, open_sockets: impl Fn() -> usize) {
+        self.stop();
+        self.tasks.close();
+        info!("shutting down, waiting for {} requests", self.tasks.len());
+        if tokio::time::timeout(timeout, self.tasks.wait()).await.is_err() {
+            warn!("shutdown timed out, abandoning {} requests", self.tasks.len());
+        }
+        self.drained.cancel();
+        let closing = async {
+            while open_sockets() > 0 {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        };
+        if tokio::time::timeout(CLOSE_GRACE, closing).await.is_err() {
+            warn!("{} sockets did not close in time", open_sockets());
+        }
+    }
+}
+
+/// Calls [`Shutdown::stop`] on the first SIGTERM or SIGINT.
+pub fn stop_on_signal(shutdown: Shutdown) -> Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = terminate.recv() => info!("received SIGTERM"),
+            _ = interrupt.recv() => info!("received SIGINT"),
+        }
+        shutdown.stop();
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_drain_waits_for_requests_until_the_deadline() {
+        let shutdown = Shutdown::new();
+        let finished = shutdown.spawn(tokio::time::sleep(Duration::from_millis(20)));
+        let stuck = shutdown.spawn(std::future::pending::<()>());
+        shutdown.drain(Duration::from_millis(200), || 0).await;
+        assert!(shutdown.is_stopping());
+        assert!(finished.is_finished());
+        assert!(!stuck.is_finished());
+        tokio::time::timeout(Duration::from_millis(10), shutdown.drained())
+            .await
+            .unwrap();
+    }
+}
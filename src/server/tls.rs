@@ -0,0 +1,129 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Context, Result};
+use tokio_rustls::{
+    rustls::{
+        server::{ClientHello, ResolvesServerCert},
+        sign::{any_supported_type, CertifiedKey},
+        Certificate, PrivateKey, ServerConfig,
+    },
+    TlsAcceptor,
+};
+use tracing::{info, warn};
+
+use crate::config::TlsConfig;
+
+/// Serves the certificate from `cert_path` and `key_path`, swapping in a new
+/// one when [`CertReloader::reload`] finds the files changed. Handshakes in
+/// progress keep the certificate they started with.
+pub struct CertReloader {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<(Arc<CertifiedKey>, Option<SystemTime>)>,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn load(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey> {
+    let open = |path: &Path| {
+        File::open(path)
+            .map(BufReader::new)
+            .with_context(|| format!("open {}", path.display()))
+    };
+    let certs = rustls_pemfile::certs(&mut open(cert_path)?)
+        .with_context(|| format!("parse {}", cert_path.display()))?;
+    if certs.is_empty() {
+        return Err(anyhow!("no certificate in {}", cert_path.display()));
+    }
+    let key = rustls_pemfile::read_all(&mut open(key_path)?)
+        .with_context(|| format!("parse {}", key_path.display()))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(key),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("no private key in {}", key_path.display()))?;
+    let key = any_supported_type(&PrivateKey(key))
+        .map_err(|e| anyhow!("unsupported key in {}: {e}", key_path.display()))?;
+    Ok(CertifiedKey::new(
+        certs.into_iter().map(Certificate).collect(),
+        key,
+    ))
+}
+
+impl CertReloader {
+    pub fn new(cert_path: PathBuf, key_path: PathBuf) -> Result<Self> {
+        let key = load(&cert_path, &key_path)?;
+        let stamp = newest(&cert_path, &key_path);
+        Ok(Self {
+            cert_path,
+            key_path,
+            current: RwLock::new((Arc::new(key), stamp)),
+        })
+    }
+
+    /// Reloads the certificate if either file changed since the last load.
+    /// A broken or half-written pair is logged and the old one kept.
+    pub fn reload(&self) {
+        let stamp = newest(&self.cert_path, &self.key_path);
+        if stamp == self.current.read().unwrap().1 {
+            return;
+        }
+        match load(&self.cert_path, &self.key_path) {
+            Ok(key) => {
+                *self.current.write().unwrap() = (Arc::new(key), stamp);
+                info!("reloaded tls certificate {}", self.cert_path.display());
+            }
+            Err(e) => warn!("reload tls certificate failed, keeping the old one: {:#}", e),
+        }
+    }
+}
+
+fn newest(cert_path: &Path, key_path: &Path) -> Option<SystemTime> {
+    modified(cert_path).max(modified(key_path))
+}
+
+impl ResolvesServerCert for CertReloader {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().0.clone())
+    }
+}
+
+/// The acceptor for `[tls]`, `None` when TLS is off. Starts a task that
+/// checks the files every `reload_interval_secs`.
+pub fn acceptor(config: &TlsConfig) -> Result<Option<(TlsAcceptor, Arc<CertReloader>)>> {
+    let (Some(cert_path), Some(key_path)) = (&config.cert_path, &config.key_path) else {
+        return Ok(None);
+    };
+    let reloader = Arc::new(CertReloader::new(cert_path.clone(), key_path.clone())?);
+    let mut server = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(reloader.clone());
+    server.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    let interval = Duration::from_secs(config.reload_interval_secs.max(1));
+    let watched = Arc::downgrade(&reloader);
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            let Some(reloader) = watched.upgrade() else {
+                break;
+            };
+            reloader.reload();
+        }
+    });
+    Ok(Some((TlsAcceptor::from(Arc::new(server)), reloader)))
+}
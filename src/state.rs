@@ -0,0 +1,100 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use crate::{
+    auth::{
+        api_key::{ApiKeyStore, FileApiKeyStore},
+        jwt::Keys,
+        oidc::{FileOidcLinkStore, OidcLinkStore},
+        repository::{self, UserRepository},
+        revocation::{FileRevocationStore, RevocationStore},
+        throttle::{LoginThrottle, ThrottleConfig},
+        totp::RedeemedChallenges,
+    },
+    config::SharedConfig,
+    utils::audit::{AuditLog, FileAuditLog},
+};
+
+/// Keys and stores built from the [`Config`](crate::config::Config) at
+/// startup. Routes reach it through axum state, the WebSocket through
+/// [`WsState`](crate::ws::state::WsState).
+pub struct AppState {
+    pub config: Arc<SharedConfig>,
+    pub keys: Keys,
+    pub users: Box<dyn UserRepository>,
+    pub revocations: Box<dyn RevocationStore>,
+    pub api_keys: Box<dyn ApiKeyStore>,
+    pub oidc_links: Box<dyn OidcLinkStore>,
+    pub throttle: LoginThrottle,
+    pub challenges: RedeemedChallenges,
+    pub audit: Box<dyn AuditLog>,
+}
+
+impl AppState {
+    /// Opens everything `config` names. An unreadable key or store fails
+    /// here, before the server accepts requests.
+    pub fn open(config: Arc<SharedConfig>) -> Result<Self> {
+        let current = config.get();
+        let storage = &current.storage;
+        Ok(Self {
+            keys: Keys::new(&current.auth)?,
+            users: repository::open(&current)?,
+            revocations: Box::new(FileRevocationStore::open(&storage.token_db_path)?),
+            api_keys: Box::new(FileApiKeyStore::open(&storage.api_key_db_path)?),
+            oidc_links: Box::new(FileOidcLinkStore::open(&storage.oidc_link_db_path)?),
+            throttle: LoginThrottle::new(ThrottleConfig::from(&current.login)),
+            challenges: RedeemedChallenges::default(),
+            audit: Box::new(FileAuditLog::open(&storage.audit_log_path)?),
+            config,
+        })
+    }
+
+    /// Applies the login limits of every later reload.
+    pub fn follow_reloads(self: &Arc<Self>) {
+        let app = self.clone();
+        let mut updates = app.config.subscribe();
+        tokio::spawn(async move {
+            while updates.changed().await.is_ok() {
+                let login = ThrottleConfig::from(&updates.borrow_and_update().login);
+                app.throttle.set_config(login);
+            }
+        });
+    }
+
+    /// A state of its own, with every store in a fresh temp directory.
+    #[cfg(test)]
+    pub(crate) fn in_temp_dir() -> Self {
+        let dir = std::env::temp_dir().join(format!("app-{}", uuid::Uuid::new_v4()));
+        let env = move |key: &str| {
+            let value = match key {
+                "JWT_SECRET" | "JWT_REFRESH_SECRET" => key.to_owned(),
+                "OPENAI_API_KEY" | "AZURE_TTS_KEY" | "AZURE_TTS_REGION" => "x".to_owned(),
+                "USER_DB_PATH" => dir.join("users.json").display().to_string(),
+                "TOKEN_DB_PATH" => dir.join("tokens.json").display().to_string(),
+                "API_KEY_DB_PATH" => dir.join("api_keys.json").display().to_string(),
+                "AUDIT_LOG_PATH" => dir.join("audit.jsonl").display().to_string(),
+                "OIDC_LINK_DB_PATH" => dir.join("oidc_links.json").display().to_string(),
+                _ => return None,
+            };
+            Some(value)
+        };
+        let config = SharedConfig::with_env(Vec::new(), Box::new(env)).unwrap();
+        Self::open(Arc::new(config)).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{Auth, Role};
+
+    #[test]
+    fn test_states_are_isolated() {
+        let (a, b) = (AppState::in_temp_dir(), AppState::in_temp_dir());
+        let salt = uuid::Uuid::new_v4().to_string();
+        let user = Auth::create(&a, "ann".into(), "pw", salt, Role::Parent, None).unwrap();
+        assert_eq!(Auth::new_by_name(&a, "ann".into()).unwrap().id, user.id);
+        assert!(Auth::new_by_name(&b, "ann".into()).is_err());
+    }
+}
@@ -0,0 +1,344 @@
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{header, request::Parts},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_QUERY_LIMIT: usize = 100;
+const MAX_QUERY_LIMIT: usize = 1000;
+/// Deepest page reachable with `offset`, older events need `until`.
+const MAX_QUERY_OFFSET: usize = 10_000;
+
+/// What happened. Together with [`Outcome`] this is the stable part of an
+/// audit record that alerts and queries can rely on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditKind {
+    /// Password login, failing also when the user is throttled.
+    Login,
+    /// Second step of a login with two-factor authentication.
+    SecondFactor,
+    Refresh,
+    Logout,
+    /// Authentication of a WebSocket, on upgrade or in-band.
+    WsAuth,
+    /// A WebSocket message whose `from` was not its sender.
+    ForgedSender,
+    /// A WebSocket message the sender's role does not allow.
+    Denied,
+    PasswordChanged,
+    TwoFactorEnabled,
+    TwoFactorDisabled,
+    ApiKeyCreated,
+    ApiKeyRevoked,
+    SessionClosed,
+    /// An administrator lifted a login lockout.
+    Unlock,
+    /// The configuration was reloaded, by SIGHUP or an administrator.
+    ConfigReloaded,
+    /// An administrator linked a single sign-on subject to a user.
+    OidcLinked,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Success,
+    Failure,
+}
+
+/// Outcome of an operation that returned `result`.
+pub fn outcome<T, E>(result: &Result<T, E>) -> Outcome {
+    if result.is_ok() {
+        Outcome::Success
+    } else {
+        Outcome::Failure
+    }
+}
+
+/// One line of the audit log. `uid` and `name` identify the actor as far as
+/// it is known, e.g. failed logins only have the name that was tried.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AuditEvent {
+    pub time: i64,
+    pub kind: AuditKind,
+    pub outcome: Outcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl AuditEvent {
+    pub fn new(kind: AuditKind, outcome: Outcome) -> Self {
+        Self {
+            time: chrono::Utc::now().timestamp(),
+            kind,
+            outcome,
+            uid: None,
+            name: None,
+            ip: None,
+            user_agent: None,
+            detail: None,
+        }
+    }
+
+    pub fn success(kind: AuditKind) -> Self {
+        Self::new(kind, Outcome::Success)
+    }
+
+    pub fn failure(kind: AuditKind) -> Self {
+        Self::new(kind, Outcome::Failure)
+    }
+
+    pub fn uid(mut self, uid: u64) -> Self {
+        self.uid = Some(uid);
+        self
+    }
+
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn actor(self, uid: u64, name: impl Into<String>) -> Self {
+        self.uid(uid).name(name)
+    }
+
+    pub fn client(mut self, client: &Client) -> Self {
+        self.ip = Some(client.addr.ip());
+        self.user_agent.clone_from(&client.user_agent);
+        self
+    }
+
+    pub fn addr(mut self, addr: SocketAddr) -> Self {
+        self.ip = Some(addr.ip());
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = Some(user_agent.into());
+        self
+    }
+
+    pub fn detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    /// Appends the event to `log` and mirrors it to the `audit` tracing
+    /// target. Failing to persist is logged but never fails the request.
+    pub fn record(self, log: &dyn AuditLog) {
+        let line = serde_json::to_string(&self).unwrap_or_default();
+        match self.outcome {
+            Outcome::Success => tracing::info!(target: "audit", "{}", line),
+            Outcome::Failure => tracing::warn!(target: "audit", "{}", line),
+        }
+        if let Err(e) = log.append(&self) {
+            tracing::error!("write audit log failed: {:?}", e);
+        }
+    }
+}
+
+/// Filters of [`AuditLog::query`], all optional. `since` and `until` are unix
+/// timestamps and inclusive. `offset` skips that many of the newest matches
+/// to page through them.
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditQuery {
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    pub uid: Option<u64>,
+    pub name: Option<String>,
+    pub kind: Option<AuditKind>,
+    pub outcome: Option<Outcome>,
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+}
+
+impl AuditQuery {
+    fn matches(&self, event: &AuditEvent) -> bool {
+        self.since.is_none_or(|t| event.time >= t)
+            && self.until.is_none_or(|t| event.time <= t)
+            && self.uid.is_none_or(|uid| event.uid == Some(uid))
+            && self.name.as_ref().is_none_or(|n| event.name.as_ref() == Some(n))
+            && self.kind.is_none_or(|k| event.kind == k)
+            && self.outcome.is_none_or(|o| event.outcome == o)
+    }
+}
+
+/// Append-only store of audit events.
+pub trait AuditLog: Send + Sync {
+    fn append(&self, event: &AuditEvent) -> Result<()>;
+    /// Matching events, newest first.
+    fn query(&self, query: &AuditQuery) -> Result<Vec<AuditEvent>>;
+}
+
+impl<T: AuditLog + ?Sized> AuditLog for Box<T> {
+    fn append(&self, event: &AuditEvent) -> Result<()> {
+        (**self).append(event)
+    }
+
+    fn query(&self, query: &AuditQuery) -> Result<Vec<AuditEvent>> {
+        (**self).query(query)
+    }
+}
+
+/// Audit log kept as one JSON object per line. The file is only ever opened
+/// for appending, rotating it is left to the operator.
+pub struct FileAuditLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl FileAuditLog {
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("open {}", path.display()))?;
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl AuditLog for FileAuditLog {
+    fn append(&self, event: &AuditEvent) -> Result<()> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap();
+        file.write_all(&line)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    fn query(&self, query: &AuditQuery) -> Result<Vec<AuditEvent>> {
+        let limit = query
+            .limit
+            .unwrap_or(DEFAULT_QUERY_LIMIT)
+            .clamp(1, MAX_QUERY_LIMIT);
+        let offset = query.offset.unwrap_or(0).min(MAX_QUERY_OFFSET);
+        let file =
+            File::open(&self.path).with_context(|| format!("open {}", self.path.display()))?;
+        // Only the newest `offset + limit` matches are kept while scanning.
+        let keep = offset + limit;
+        let mut events = VecDeque::with_capacity(keep);
+        for line in BufReader::new(file).lines() {
+            // A line cut short by a crash is skipped rather than failing the
+            // whole query.
+            let Ok(event) = serde_json::from_str::<AuditEvent>(&line?) else {
+                continue;
+            };
+            if query.matches(&event) {
+                if events.len() == keep {
+                    events.pop_front();
+                }
+                events.push_back(event);
+            }
+        }
+        Ok(events.into_iter().rev().skip(offset).collect())
+    }
+}
+
+/// Address and user agent of the caller, for audit records.
+pub struct Client {
+    pub addr: SocketAddr,
+    pub user_agent: Option<String>,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Client
+where
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let ConnectInfo(addr) = ConnectInfo::<SocketAddr>::from_request_parts(parts, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        let user_agent = parts
+            .headers
+            .get(header::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_owned);
+        Ok(Self { addr, user_agent })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_filters_newest_first() {
+        let dir = std::env::temp_dir().join(format!("audit-{}", uuid::Uuid::new_v4()));
+        let log = FileAuditLog::open(dir.join("audit.jsonl")).unwrap();
+        let events = [
+            (10, 1, Outcome::Success),
+            (20, 2, Outcome::Failure),
+            (30, 1, Outcome::Failure),
+        ];
+        for (time, uid, outcome) in events {
+            let mut event = AuditEvent::new(AuditKind::Login, outcome).uid(uid);
+            event.time = time;
+            log.append(&event).unwrap();
+        }
+        let query = AuditQuery {
+            uid: Some(1),
+            ..Default::default()
+        };
+        let times = |q: &AuditQuery| {
+            log.query(q).unwrap().iter().map(|e| e.time).collect::<Vec<_>>()
+        };
+        assert_eq!(times(&query), vec![30, 10]);
+        let query = AuditQuery {
+            since: Some(15),
+            until: Some(30),
+            limit: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(times(&query), vec![30]);
+        let query = AuditQuery {
+            outcome: Some(Outcome::Failure),
+            kind: Some(AuditKind::Refresh),
+            ..Default::default()
+        };
+        assert!(times(&query).is_empty());
+
+        let page = |offset| AuditQuery {
+            kind: Some(AuditKind::Login),
+            limit: Some(2),
+            offset: Some(offset),
+            ..Default::default()
+        };
+        assert_eq!(times(&page(0)), vec![30, 20]);
+        assert_eq!(times(&page(2)), vec![10]);
+        assert!(times(&page(3)).is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,4 +1,4 @@
-use std::{path::PathBuf, str};
+use std::{path::Path, str};
 
 use anyhow::Result;
 use aspeak::{
@@ -8,8 +8,9 @@ use aspeak::{
 use blake2::{Blake2s256, Digest};
 use tokio::fs;
 
-#[allow(dead_code)]
+use crate::config::AzureTtsConfig;
 
+#[allow(dead_code)]
 pub struct TextConfigOptions {
     rate: String,
     voice: String,
@@ -37,12 +38,24 @@ impl TextConfigOptions {
     }
 }
 
-async fn text_to_speech(azure_api_key: &str, region: &str, msg: &str) -> Result<Vec<u8>> {
+impl From<&AzureTtsConfig> for TextConfigOptions {
+    fn from(config: &AzureTtsConfig) -> Self {
+        Self {
+            rate: config.rate.clone(),
+            voice: config.voice.clone(),
+            pitch: config.pitch.clone(),
+            style: config.style.clone(),
+        }
+    }
+}
+
+async fn text_to_speech(azure_tts: &AzureTtsConfig, msg: &str) -> Result<Vec<u8>> {
+    let region = azure_tts.region.as_deref().unwrap_or_default();
     let auth = AuthOptionsBuilder::new(get_rest_endpoint_by_region(region))
-        .key(azure_api_key)
+        .key(azure_tts.key.as_deref().unwrap_or_default())
         .build();
     let config = SynthesizerConfig::new(auth, AudioFormat::Audio16Khz32KBitRateMonoMp3);
-    let options = TextConfigOptions::new_en();
+    let options = TextConfigOptions::from(azure_tts);
     let options = TextOptionsBuilder::new() // Adjusting text options like rate, pitch and voice
         .rate(options.rate)
         .voice(options.voice)
@@ -63,18 +76,22 @@ pub fn hash(msg: &str) -> Result<String> {
     Ok(hex::encode(res))
 }
 
-pub async fn fetch_speed(azure_tts_key: &str, region: &str, msg: &str) -> Result<String> {
-    let name = hash(msg)?;
+/// Synthesizes `msg` into `cache_dir` unless it is cached already, and
+/// returns the file name, which is also its path on the static file routes.
+/// The name covers the voice settings, so changing them takes effect for
+/// cached texts too.
+pub async fn fetch_speed(azure_tts: &AzureTtsConfig, cache_dir: &Path, msg: &str) -> Result<String> {
+    let voice = [&azure_tts.voice, &azure_tts.rate, &azure_tts.pitch, &azure_tts.style];
+    let name = hash(&format!("{}\0{msg}", voice.map(String::as_str).join("\0")))?;
     let result = format!("{name}.mp3");
-    let assets_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("assets");
 
-    let path = assets_dir.join(&result);
-    dbg!("assets_dir: {:?}", &path);
+    let path = cache_dir.join(&result);
     {
         if !path.exists() {
-            let res: Vec<u8> = text_to_speech(azure_tts_key, region, msg)
+            let res: Vec<u8> = text_to_speech(azure_tts, msg)
                 .await
                 .map_err(|e| anyhow::anyhow!("text_to_speech error: {:?}", e))?;
+            fs::create_dir_all(cache_dir).await?;
             fs::write(path, res).await?;
         };
     }
@@ -1,8 +1,14 @@
-use std::sync::Arc;
+use std::{fmt, sync::Arc};
 
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::auth::Role;
+
+/// `to` value that addresses every connected socket. It is JavaScript's
+/// `Number.MAX_SAFE_INTEGER` so browser clients can send it exactly.
+pub const BROADCAST: u64 = (1 << 53) - 1;
+
 #[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
 pub enum Event {
     #[serde(rename = "chat")]
@@ -11,11 +17,95 @@ pub enum Event {
     Speech(String),
     #[serde(rename = "loading")]
     Loading(bool),
+    /// A request failed. Always followed by `Loading(false)`.
     #[serde(rename = "error")]
-    ServerError(String),
+    ServerError(ErrorDetail),
+    /// Sent by the client with a fresh access token to extend the session.
+    #[serde(rename = "auth")]
+    Auth(String),
+    /// Confirms [`Event::Auth`] with the new expiry as a unix timestamp.
+    #[serde(rename = "authenticated")]
+    Authenticated(i64),
+    /// Seconds left before the socket is closed for an expired token.
+    #[serde(rename = "tokenExpiring")]
+    TokenExpiring(i64),
+    /// Sent before the socket is closed for a server shutdown or restart.
+    #[serde(rename = "serverRestarting")]
+    ServerRestarting(String),
+    /// Sent by the client with the `msgId` of a request it no longer needs.
+    #[serde(rename = "cancel")]
+    Cancel(String),
+    /// Confirms [`Event::Cancel`] with the `msgId` of the cancelled request,
+    /// which then only gets `Loading(false)`.
+    #[serde(rename = "cancelled")]
+    Cancelled(String),
+}
+
+/// Why a request failed. The serialized names are stable, clients may match
+/// on them.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The payload does not fit the event type.
+    BadRequest,
+    /// No handler for the event type.
+    UnknownEvent,
+    /// The role of the socket does not allow the request.
+    Forbidden,
+    /// In-band re-authentication was refused.
+    AuthFailed,
+    /// The server as a whole has too many requests waiting.
+    Busy,
+    /// The user has too many requests waiting.
+    TooManyRequests,
+    /// Nothing to cancel, the request already finished or never existed.
+    UnknownRequest,
+    /// ChatGPT or the speech service failed.
+    UpstreamFailed,
+    /// The server is shutting down, retry after reconnecting.
+    ShuttingDown,
+    Internal,
+}
+
+impl ErrorCode {
+    /// Whether the same request may succeed later.
+    pub const fn retryable(self) -> bool {
+        matches!(
+            self,
+            Self::Busy | Self::TooManyRequests | Self::UpstreamFailed | Self::ShuttingDown
+        )
+    }
 }
 
+/// Payload of [`Event::ServerError`]. Handlers return it through `anyhow` to
+/// choose the code, other errors are reported as
+/// [`ErrorCode::UpstreamFailed`].
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ErrorDetail {
+    pub code: ErrorCode,
+    pub message: String,
+    pub retryable: bool,
+}
+
+impl ErrorDetail {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            retryable: code.retryable(),
+        }
+    }
+}
+
+impl fmt::Display for ErrorDetail {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for ErrorDetail {}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum EventType {
     #[serde(rename = "chat")]
     Chat,
@@ -25,6 +115,35 @@ pub enum EventType {
     Loading,
     #[serde(rename = "error")]
     ServerError,
+    #[serde(rename = "auth")]
+    Auth,
+    #[serde(rename = "authenticated")]
+    Authenticated,
+    #[serde(rename = "tokenExpiring")]
+    TokenExpiring,
+    #[serde(rename = "serverRestarting")]
+    ServerRestarting,
+    #[serde(rename = "cancel")]
+    Cancel,
+    #[serde(rename = "cancelled")]
+    Cancelled,
+}
+
+impl EventType {
+    /// Least privileged role allowed to send this event type to another
+    /// user. Requests to the server are checked against their handler.
+    pub const fn required_role(&self) -> Role {
+        match self {
+            Self::Chat | Self::Speech | Self::Auth | Self::Cancel => Role::Child,
+            // Only the server emits these.
+            Self::Loading
+            | Self::ServerError
+            | Self::Authenticated
+            | Self::TokenExpiring
+            | Self::ServerRestarting
+            | Self::Cancelled => Role::Admin,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
@@ -43,7 +162,99 @@ pub struct WsRequest {
 #[derive(Debug)]
 pub struct ChannelMessage {
     pub uuid: Arc<Uuid>,
+    /// Authenticated sender, `body.from` is always stamped with it.
+    pub uid: u64,
     pub body: WsRequest,
 }
 
 pub type WsResponse = WsRequest;
+
+impl WsResponse {
+    /// Whether the request `reply_msg_id` of user `to` is being handled.
+    pub fn loading(to: u64, reply_msg_id: &str, loading: bool) -> Self {
+        Self {
+            event: Event::Loading(loading),
+            event_type: EventType::Loading,
+            msg_id: Uuid::new_v4().to_string(),
+            from: 0,
+            to,
+            reply_msg_id: Some(reply_msg_id.to_owned()),
+        }
+    }
+
+    /// The terminal events of a failed request: the error, then
+    /// `Loading(false)`.
+    pub fn failure(to: u64, reply_msg_id: &str, error: ErrorDetail) -> [Self; 2] {
+        let error = Self {
+            event: Event::ServerError(error),
+            event_type: EventType::ServerError,
+            msg_id: Uuid::new_v4().to_string(),
+            from: 0,
+            to,
+            reply_msg_id: Some(reply_msg_id.to_owned()),
+        };
+        [error, Self::loading(to, reply_msg_id, false)]
+    }
+
+    /// Whether a full socket queue may drop this event: messages relayed from
+    /// other users and `Loading(true)`. Everything else the server sends ends
+    /// a request or tells the client about its session, so it is kept and a
+    /// slow client is never left waiting on a request that already finished.
+    pub const fn droppable(&self) -> bool {
+        self.from != 0 || matches!(self.event, Event::Loading(true))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failure_wire_format() {
+        let error = ErrorDetail::new(ErrorCode::TooManyRequests, "slow down");
+        let [error, loading] = WsResponse::failure(7, "m1", error);
+        let error = serde_json::to_value(error).unwrap();
+        assert_eq!(
+            error["event"],
+            serde_json::json!({
+                "error": {"code": "too_many_requests", "message": "slow down", "retryable": true}
+            })
+        );
+        assert_eq!(error["eventType"], "error");
+        assert_eq!(error["replyMsgId"], "m1");
+        assert_eq!(loading.event, Event::Loading(false));
+        assert_eq!(loading.reply_msg_id.as_deref(), Some("m1"));
+    }
+
+    #[tokio::test]
+    async fn test_flooded_socket_queue_keeps_replies() {
+        use crate::utils::queue::{bounded_keeping, Overflow};
+
+        let (s, mut r) = bounded_keeping(4, Overflow::DropOldest, |m: &WsRequest| !m.droppable());
+        let relayed = |i: u64| WsRequest {
+            from: 5,
+            to: 7,
+            event: Event::Chat(i.to_string()),
+            event_type: EventType::Chat,
+            msg_id: i.to_string(),
+            reply_msg_id: None,
+        };
+        s.send(WsResponse::loading(7, "m1", true)).unwrap();
+        s.send(WsResponse {
+            from: 0,
+            reply_msg_id: Some("m1".into()),
+            ..relayed(0)
+        })
+        .unwrap();
+        for i in 1..100 {
+            s.send(relayed(i)).unwrap();
+        }
+        let mut received = Vec::new();
+        while let Some(m) = r.try_recv() {
+            received.push(m);
+        }
+        assert_eq!(received.len(), 4);
+        assert_eq!(received[0].reply_msg_id.as_deref(), Some("m1"));
+        assert_eq!(received[3].msg_id, "99");
+    }
+}
@@ -1,4 +1,7 @@
 pub mod azure_tts;
 pub mod openai;
 
+pub mod audit;
 pub mod event;
+pub mod queue;
+pub mod redact;
@@ -1,34 +1,40 @@
+use std::panic::AssertUnwindSafe;
+
 use anyhow::{Result, anyhow};
+use futures::FutureExt;
 use openai_dive::v1::{
     api::Client,
     resources::chat::{ChatCompletionParameters, ChatCompletionResponse, ChatMessage, Role},
 };
 
+use crate::config::OpenAiConfig;
+
 pub async fn chat(
     openai_api_key: &str,
+    model: &str,
     messages: &[ChatMessage],
 ) -> Result<ChatCompletionResponse> {
     let client = Client::new(openai_api_key.to_owned());
     let parameters = ChatCompletionParameters {
-        model: "gpt-3.5-turbo".to_string(),
+        model: model.to_string(),
         messages: messages.into(),
         ..Default::default()
     };
-    let result = client.chat().create(parameters).await.map_err(|e| anyhow!("{}", e))?;
+    // openai_dive unwraps transport errors, the panic becomes an error. No
+    // task of its own, so a cancelled request drops the connection.
+    let result = AssertUnwindSafe(client.chat().create(parameters))
+        .catch_unwind()
+        .await
+        .map_err(|_| anyhow!("openai request failed: transport error"))?
+        .map_err(|e| anyhow!("{}", e))?;
     Ok(result)
 }
 
-pub fn get_en_teacher_chat_message(msg: &str) -> Vec<ChatMessage> {
+pub fn get_en_teacher_chat_message(system_prompt: &str, msg: &str) -> Vec<ChatMessage> {
     vec![
         ChatMessage {
             role: Role::System,
-            content: Some(
-                "Suppose you are a kindergarten English starter teacher, I am going to ask you \
-                 some simple words and ask you to say his English and give as much English \
-                 explanation and example sentences as possible，and mark the English portion with \
-                 ``, such as `foo`."
-                    .to_string(),
-            ),
+            content: Some(system_prompt.to_string()),
             ..Default::default()
         },
         ChatMessage {
@@ -54,7 +60,8 @@ pub fn get_en_teacher_chat_message(msg: &str) -> Vec<ChatMessage> {
     ]
 }
 
-pub async fn en_teacher_chat(openai_api_key: &str, msg: &str) -> Result<ChatCompletionResponse> {
-    let messages = get_en_teacher_chat_message(msg);
-    chat(openai_api_key, &messages).await
+pub async fn en_teacher_chat(config: &OpenAiConfig, msg: &str) -> Result<ChatCompletionResponse> {
+    let messages = get_en_teacher_chat_message(&config.system_prompt, msg);
+    let api_key = config.api_key.as_deref().unwrap_or_default();
+    chat(api_key, &config.model, &messages).await
 }
@@ -0,0 +1,262 @@
+//! Bounded single-consumer queue that, unlike `tokio::sync::mpsc`, can make
+//! room by dropping its oldest item, for events where the newest matter most.
+//! Items the queue was told to keep are passed over.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use serde::Deserialize;
+use tokio::sync::Notify;
+
+/// What [`Sender::send`] does when the queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Overflow {
+    /// Discard the oldest queued item to make room. Kept items are skipped,
+    /// the new item is discarded instead when only those are queued.
+    #[default]
+    DropOldest,
+    /// Close the queue, the consumer cannot keep up.
+    Disconnect,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendError {
+    /// The receiver is gone, or the queue was closed by an earlier overflow.
+    Closed,
+    /// This item overflowed a [`Overflow::Disconnect`] queue, which is now
+    /// closed.
+    Overflowed,
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Closed => f.write_str("queue closed"),
+            Self::Overflowed => f.write_str("queue overflowed"),
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
+struct State<T> {
+    items: VecDeque<T>,
+    closed: bool,
+    overflowed: bool,
+    dropped: u64,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    notify: Notify,
+    capacity: usize,
+    overflow: Overflow,
+    keep: fn(&T) -> bool,
+    senders: AtomicUsize,
+}
+
+impl<T> Shared<T> {
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.notify.notify_one();
+    }
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub fn bounded<T>(capacity: usize, overflow: Overflow) -> (Sender<T>, Receiver<T>) {
+    bounded_keeping(capacity, overflow, |_| false)
+}
+
+/// Like [`bounded`], but [`Overflow::DropOldest`] never drops items `keep`
+/// returns `true` for. The queue may then hold up to twice `capacity` items,
+/// past that it closes like [`Overflow::Disconnect`].
+pub fn bounded_keeping<T>(
+    capacity: usize,
+    overflow: Overflow,
+    keep: fn(&T) -> bool,
+) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            items: VecDeque::with_capacity(capacity.min(64)),
+            closed: false,
+            overflowed: false,
+            dropped: 0,
+        }),
+        notify: Notify::new(),
+        capacity: capacity.max(1),
+        overflow,
+        keep,
+        senders: AtomicUsize::new(1),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+impl<T> Sender<T> {
+    pub fn send(&self, item: T) -> Result<(), SendError> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed {
+            return Err(SendError::Closed);
+        }
+        if state.items.len() >= self.shared.capacity {
+            let keep = self.shared.keep;
+            let oldest = match self.shared.overflow {
+                Overflow::DropOldest => state.items.iter().position(|i| !keep(i)),
+                Overflow::Disconnect => None,
+            };
+            match (self.shared.overflow, oldest) {
+                (Overflow::DropOldest, Some(oldest)) => {
+                    state.items.remove(oldest);
+                    state.dropped += 1;
+                }
+                (Overflow::DropOldest, None) if !keep(&item) => {
+                    state.dropped += 1;
+                    return Ok(());
+                }
+                // Only kept items are queued, they may take a second
+                // `capacity` before the client counts as stuck.
+                (Overflow::DropOldest, None) if state.items.len() < 2 * self.shared.capacity => {}
+                _ => {
+                    state.items.clear();
+                    state.closed = true;
+                    state.overflowed = true;
+                    drop(state);
+                    self.shared.notify.notify_one();
+                    return Err(SendError::Overflowed);
+                }
+            }
+        }
+        state.items.push_back(item);
+        drop(state);
+        self.shared.notify.notify_one();
+        Ok(())
+    }
+
+    /// Items waiting for the consumer.
+    pub fn len(&self) -> usize {
+        self.shared.state.lock().unwrap().items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Items discarded by [`Overflow::DropOldest`] so far.
+    pub fn dropped(&self) -> u64 {
+        self.shared.state.lock().unwrap().dropped
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shared.close();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Next item, `None` once the queue is closed and empty. An overflowed
+    /// queue is emptied on the spot.
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            if let Some(item) = self.try_recv() {
+                return Some(item);
+            }
+            if self.shared.state.lock().unwrap().closed {
+                return None;
+            }
+            // Sends store a permit when nobody waits, so none is missed
+            // between the check above and this point.
+            self.shared.notify.notified().await;
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Option<T> {
+        self.shared.state.lock().unwrap().items.pop_front()
+    }
+
+    /// Whether the queue was closed by [`Overflow::Disconnect`].
+    pub fn overflowed(&self) -> bool {
+        self.shared.state.lock().unwrap().overflowed
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_overflow_policies() {
+        let (s, mut r) = bounded(2, Overflow::DropOldest);
+        for i in 0..4 {
+            s.send(i).unwrap();
+        }
+        assert_eq!((s.len(), s.dropped()), (2, 2));
+        assert_eq!(r.recv().await, Some(2));
+        assert_eq!(r.recv().await, Some(3));
+        drop(s);
+        assert_eq!(r.recv().await, None);
+
+        let (s, mut r) = bounded(1, Overflow::Disconnect);
+        s.send(1).unwrap();
+        assert_eq!(s.send(2), Err(SendError::Overflowed));
+        assert_eq!(s.send(3), Err(SendError::Closed));
+        assert_eq!(r.recv().await, None);
+        assert!(r.overflowed());
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest_passes_over_kept_items() {
+        let (s, mut r) = bounded_keeping(2, Overflow::DropOldest, |i: &i32| *i < 0);
+        for i in [-1, 1, 2, -2, 3] {
+            s.send(i).unwrap();
+        }
+        // 1 and 2 made room for newer items, 3 itself found only kept ones.
+        assert_eq!((s.len(), s.dropped()), (2, 3));
+        assert_eq!(r.recv().await, Some(-1));
+        assert_eq!(r.recv().await, Some(-2));
+
+        // Kept items may fill twice the capacity, one more disconnects.
+        let (s, mut r) = bounded_keeping(2, Overflow::DropOldest, |i: &i32| *i < 0);
+        for i in [-1, -2, -3, -4] {
+            s.send(i).unwrap();
+        }
+        assert_eq!(s.send(-5), Err(SendError::Overflowed));
+        assert_eq!(r.recv().await, None);
+        assert!(r.overflowed());
+    }
+}
@@ -0,0 +1,61 @@
+use std::borrow::Cow;
+
+use blake2::{Blake2s256, Digest};
+
+use crate::auth::api_key::API_KEY_PREFIX;
+
+/// Short, stable stand-in for a secret so log lines can still be correlated.
+pub fn fingerprint(token: &str) -> String {
+    let digest = Blake2s256::digest(token.as_bytes());
+    format!("<redacted:{}>", &hex::encode(digest)[..8])
+}
+
+/// Replaces everything that looks like a JWT (`eyJ` followed by three dot
+/// separated base64url segments) or an API key with its [`fingerprint`].
+pub fn redact_tokens(s: &str) -> Cow<'_, str> {
+    let next = |s: &str| [s.find("eyJ"), s.find(API_KEY_PREFIX)].into_iter().flatten().min();
+    if next(s).is_none() {
+        return Cow::Borrowed(s);
+    }
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = next(rest) {
+        out.push_str(&rest[..start]);
+        let candidate = &rest[start..];
+        let len = candidate
+            .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')))
+            .unwrap_or(candidate.len());
+        let token = &candidate[..len];
+        let is_key = token
+            .strip_prefix(API_KEY_PREFIX)
+            .is_some_and(|key| key.contains('_'));
+        if is_key || token.matches('.').count() == 2 {
+            out.push_str(&fingerprint(token));
+        } else {
+            out.push_str(token);
+        }
+        rest = &candidate[len..];
+    }
+    out.push_str(rest);
+    Cow::Owned(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact_tokens() {
+        let token = "eyJhbGciOiJIUzI1NiJ9.eyJpZCI6MX0.c2ln";
+        let uri = format!("/ws?accessToken={token}&x=1");
+        let redacted = redact_tokens(&uri);
+        assert_eq!(redacted, format!("/ws?accessToken={}&x=1", fingerprint(token)));
+        assert_eq!(redact_tokens("eyJ only"), "eyJ only");
+        let key = "cwk_0123abcd_5ec12e7";
+        assert_eq!(
+            redact_tokens(&format!("Bearer {key}")),
+            format!("Bearer {}", fingerprint(key))
+        );
+        assert_eq!(redact_tokens("cwk_ only"), "cwk_ only");
+    }
+}
@@ -1,17 +1,74 @@
 use std::{
     collections::HashMap,
-    sync::{Arc, Mutex},
+    net::SocketAddr,
+    sync::{atomic::AtomicI64, Arc, Mutex},
 };
 
+use axum::extract::ws::CloseFrame;
+use tokio::{sync::watch, task::AbortHandle};
 use uuid::Uuid;
 
-use crate::utils::event;
+use crate::{
+    auth::JWTData,
+    utils::{event, queue},
+};
 
 pub mod router;
 pub mod state;
 
 pub type Uid = u64;
 
-pub type Sender<T> = tokio::sync::mpsc::UnboundedSender<T>;
-pub type UserPeerMap = Arc<Mutex<HashMap<Arc<Uuid>, Sender<Arc<event::WsRequest>>>>>;
+pub type Sender<T> = tokio::sync::mpsc::Sender<T>;
+/// Events waiting to be written to a socket, see `server.socket_queue`.
+pub type Outbox = queue::Sender<Arc<event::WsRequest>>;
+pub type UserPeerMap = Arc<Mutex<HashMap<Arc<Uuid>, Peer>>>;
 pub type UserUUidMap = Arc<Mutex<HashMap<Uid, Vec<Arc<Uuid>>>>>;
+/// Requests to the server by socket and `msgId`, with the task handling them
+/// once started.
+pub type RequestMap = Mutex<HashMap<(Arc<Uuid>, String), Option<AbortHandle>>>;
+
+/// Close code sent when the server shuts down, "Service Restart" in RFC 6455
+/// terms. Clients should reconnect after a short delay.
+pub const CLOSE_SERVICE_RESTART: u16 = 1012;
+/// Close code sent when the client read too slowly and its queue overflowed.
+pub const CLOSE_SLOW_CONSUMER: u16 = 4005;
+/// Close code sent when the user logged out.
+pub const CLOSE_LOGGED_OUT: u16 = 4001;
+/// Close code sent when the access token of the socket expired.
+pub const CLOSE_TOKEN_EXPIRED: u16 = 4002;
+/// Close code sent when the token family of the socket was revoked.
+pub const CLOSE_TOKEN_REVOKED: u16 = 4003;
+/// Close code sent when the user closed or signed out this socket from
+/// elsewhere.
+pub const CLOSE_SIGNED_OUT: u16 = 4004;
+
+/// Capacity of [`Peer::control`]. Only pings and close requests use it.
+pub const CONTROL_QUEUE: usize = 8;
+
+/// Frames for the writer half of a socket that bypass its [`Outbox`].
+#[derive(Debug)]
+pub enum SocketMsg {
+    Close(Option<CloseFrame<'static>>),
+    Ping,
+}
+
+/// What is known about the client of a socket.
+pub struct SessionInfo {
+    pub uid: Uid,
+    pub user_agent: String,
+    pub addr: SocketAddr,
+    pub connected_at: i64,
+    /// Time of the last frame received from the client.
+    pub last_activity: AtomicI64,
+    /// Claims the socket is currently authenticated with.
+    pub claims: watch::Receiver<JWTData>,
+}
+
+/// A live socket: `sender` carries events routed by the channel, `control`
+/// reaches the socket writer directly.
+#[derive(Clone)]
+pub struct Peer {
+    pub sender: Outbox,
+    pub control: Sender<SocketMsg>,
+    pub session: Arc<SessionInfo>,
+}
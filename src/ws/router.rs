@@ -1,30 +1,56 @@
-use std::{collections::HashMap, net::SocketAddr, ops::ControlFlow, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    ops::ControlFlow,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use axum::{
     extract::{
-        ws::{Message, WebSocket},
+        ws::{CloseFrame, Message, WebSocket},
         ConnectInfo, Query, State, WebSocketUpgrade,
     },
-    http::StatusCode,
+    http::{header, HeaderMap, StatusCode},
     response::IntoResponse,
     routing::{any, get},
     Router,
 };
 use axum_extra::{headers, TypedHeader};
-use futures_util::{SinkExt, StreamExt};
-use jsonwebtoken::{Algorithm, Validation};
+use futures_util::{stream::SplitSink, SinkExt, StreamExt};
 use serde::Deserialize;
 // use flume::{unbounded, Sender};
-use tokio::sync::mpsc;
+use tokio::sync::{
+    mpsc::{self, error::TrySendError},
+    watch,
+};
 use tracing::{debug, info};
 use uuid::Uuid;
 
-use super::state::WsState;
+use super::{
+    state::WsState, Outbox, Peer, SessionInfo, Sender, SocketMsg, CLOSE_SERVICE_RESTART,
+    CLOSE_SLOW_CONSUMER, CLOSE_TOKEN_EXPIRED, CLOSE_TOKEN_REVOKED, CONTROL_QUEUE,
+};
 use crate::{
-    auth::{jwt, JWTData},
-    utils::event,
+    auth::{api_key::Scope, Auth, JWTData, Role},
+    server::shutdown::Shutdown,
+    state::AppState,
+    utils::{
+        audit::{AuditEvent, AuditKind, Outcome},
+        event::{self, ErrorCode, ErrorDetail},
+        queue,
+        redact::{fingerprint, redact_tokens},
+    },
 };
 
+/// How long before expiry the client is warned with `tokenExpiring`.
+const TOKEN_EXPIRING_NOTICE: i64 = 60;
+/// How often a socket re-checks whether its token family was revoked.
+const REVOCATION_CHECK_INTERVAL: i64 = 15;
+
 pub fn router(state: Arc<WsState>) -> Router {
     Router::new()
         .route("/", get(ws_handler))
@@ -36,7 +62,6 @@ pub async fn handler404() -> impl IntoResponse {
     (StatusCode::NOT_FOUND, "404 Not Found").into_response()
 }
 
-type Sender<T> = mpsc::UnboundedSender<T>;
 #[derive(Deserialize)]
 pub struct SubjectArgs {
     #[serde(rename = "accessToken")]
@@ -47,172 +72,525 @@ pub fn insert(state: Arc<WsState>, uid: u64, uuid: Arc<Uuid>) {
     state.insert_user_uuid_map(uid, uuid);
 }
 
+/// Subprotocol that marks the next offered subprotocol as an access token,
+/// e.g. `Sec-WebSocket-Protocol: bearer, <token>`. Browsers cannot set an
+/// `Authorization` header on a WebSocket, but they can offer subprotocols.
+const BEARER_PROTOCOL: &str = "bearer";
+
+/// Where the access token of an upgrade request was found.
+#[derive(Debug, Clone, Copy)]
+enum TokenSource {
+    Header,
+    Protocol,
+    Query,
+}
+
+/// The token of an upgrade request. The legacy `?accessToken=` form is only
+/// read with `allow_query`, see `server.ws_query_token`.
+fn access_token(
+    headers: &HeaderMap,
+    query: &HashMap<String, String>,
+    allow_query: bool,
+) -> Option<(String, TokenSource)> {
+    if let Some(token) = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+    {
+        return Some((token.trim().to_owned(), TokenSource::Header));
+    }
+    if let Some(protocols) = headers
+        .get(header::SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|v| v.to_str().ok())
+    {
+        let mut protocols = protocols.split(',').map(str::trim);
+        if protocols.any(|p| p.eq_ignore_ascii_case(BEARER_PROTOCOL)) {
+            if let Some(token) = protocols.next() {
+                return Some((token.to_owned(), TokenSource::Protocol));
+            }
+        }
+    }
+    if allow_query {
+        if let Some(token) = query.get("accessToken") {
+            return Some((token.to_owned(), TokenSource::Query));
+        }
+    }
+    None
+}
+
 pub async fn ws_handler(
     ws: WebSocketUpgrade,
     State(state): State<Arc<WsState>>,
     Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
     user_agent: Option<TypedHeader<headers::UserAgent>>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
 ) -> impl IntoResponse {
-    info!("ws_handler query: {:?}", query);
-    if !query.contains_key("accessToken") {
-        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
-    }
-    let access_token = query.get("accessToken").unwrap();
-    debug!("ws_handler token: {}", access_token);
-    let user = jsonwebtoken::decode::<JWTData>(
-        access_token,
-        &jwt::KEYS.decoding,
-        &Validation::new(Algorithm::HS256),
-    );
-    if user.is_err() {
-        info!("user {} unauthorized", addr);
-        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
-    };
-    let uid = user.unwrap().claims.id;
     let user_agent = if let Some(TypedHeader(user_agent)) = user_agent {
         user_agent.to_string()
     } else {
         String::from("Unknown browser")
     };
+    let audit = |outcome| {
+        AuditEvent::new(AuditKind::WsAuth, outcome)
+            .addr(addr)
+            .user_agent(&user_agent)
+    };
+    if state.shutdown.is_stopping() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Server is restarting").into_response();
+    }
+    let allow_query = state.config.get().server.ws_query_token;
+    let Some((access_token, source)) = access_token(&headers, &query, allow_query) else {
+        info!("user {} sent no access token", addr);
+        audit(Outcome::Failure)
+            .detail("no access token")
+            .record(&state.app.audit);
+        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+    };
+    debug!("ws_handler token from {:?}: {}", source, fingerprint(&access_token));
+    let claims = match socket_claims(&state.app, &access_token) {
+        Ok(claims) if Auth::new_by_id(&state.app, claims.id).is_ok() => claims,
+        result => {
+            info!("user {} unauthorized", addr);
+            let reason = result.map_or_else(|e| e.to_string(), |_| "unknown user".into());
+            audit(Outcome::Failure)
+                .detail(format!("{reason} ({source:?})"))
+                .record(&state.app.audit);
+            return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+        }
+    };
+    let uid = claims.id;
+    audit(Outcome::Success)
+        .actor(uid, &claims.name)
+        .detail(format!("{source:?}"))
+        .record(&state.app.audit);
     info!("user {} {} connected from {}", uid, user_agent, addr);
     let uuid = Arc::new(Uuid::new_v4());
-    ws.on_upgrade(move |socket| handle_socket(state.clone(), uid, uuid, socket, addr))
+    ws.protocols([BEARER_PROTOCOL])
+        .on_upgrade(move |socket| {
+            handle_socket(state.clone(), claims, uuid, socket, addr, user_agent)
+        })
 }
 
-fn insert_sender(state: Arc<WsState>, uuid: Arc<Uuid>, sender: Sender<Arc<event::WsRequest>>) {
-    state.insert_user_peer_map(uuid, sender);
+/// Claims of an access token, or of an API key with the `chat` scope.
+fn socket_claims(app: &AppState, credential: &str) -> anyhow::Result<JWTData> {
+    let claims = Auth::authenticate(app, credential)?;
+    if !claims.allows(Scope::Chat) {
+        return Err(anyhow::anyhow!("api key lacks the chat scope"));
+    }
+    Ok(claims)
+}
+
+/// Per-socket context needed while processing inbound frames.
+struct Connection {
+    uid: u64,
+    uuid: Arc<Uuid>,
+    who: SocketAddr,
+    claims: watch::Sender<JWTData>,
+    reply: Outbox,
+    session: Arc<SessionInfo>,
+}
+
+impl Connection {
+    fn audit(&self, kind: AuditKind, outcome: Outcome) -> AuditEvent {
+        AuditEvent::new(kind, outcome)
+            .actor(self.uid, &self.claims.borrow().name)
+            .addr(self.who)
+            .user_agent(&self.session.user_agent)
+    }
 }
 
-enum SocketMsg {
-    Close,
-    Ping,
-    Msg(Arc<event::WsRequest>),
+/// Warns the client shortly before its access token expires and closes the
+/// socket once it has expired or its token family is revoked. Claims replaced
+/// through in-band re-authentication restart the countdown.
+async fn guard_session(
+    app: Arc<AppState>,
+    mut claims: watch::Receiver<JWTData>,
+    reply: Outbox,
+    control: Sender<SocketMsg>,
+) {
+    let mut warned = false;
+    loop {
+        let current = claims.borrow_and_update().clone();
+        let now = chrono::Utc::now().timestamp();
+        let close = if Auth::is_revoked(&app, &current) {
+            Some((CLOSE_TOKEN_REVOKED, "token revoked"))
+        } else if now >= current.exp {
+            Some((CLOSE_TOKEN_EXPIRED, "token expired"))
+        } else {
+            None
+        };
+        if let Some((code, reason)) = close {
+            let _ = control.try_send(SocketMsg::Close(Some(CloseFrame {
+                code,
+                reason: reason.into(),
+            })));
+            return;
+        }
+        let warn_at = current.exp - TOKEN_EXPIRING_NOTICE;
+        if !warned && now >= warn_at {
+            warned = true;
+            let _ = reply.send(Arc::new(event::WsResponse {
+                event: event::Event::TokenExpiring(current.exp - now),
+                event_type: event::EventType::TokenExpiring,
+                msg_id: Uuid::new_v4().to_string(),
+                from: 0,
+                to: current.id,
+                reply_msg_id: None,
+            }));
+        }
+        let next = if warned { current.exp } else { warn_at };
+        let wait = (next - now).clamp(1, REVOCATION_CHECK_INTERVAL) as u64;
+        tokio::select! {
+            changed = claims.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+                warned = false;
+            }
+            _ = tokio::time::sleep(Duration::from_secs(wait)) => {}
+        }
+    }
 }
 
 async fn handle_socket(
     state: Arc<WsState>,
-    uid: u64,
+    claims: JWTData,
     uuid: Arc<Uuid>,
     socket: WebSocket,
     who: SocketAddr,
+    user_agent: String,
 ) {
-    // socket.close().await.unwrap();
-    let (mut sender, mut receiver) = socket.split();
-    // sender.send(Message::Close(
-    //     Some(CloseFrame {
-    //         code: 403,
-    //         reason: "Forbidden".into(),
-    //     }),
-    // )).await.unwrap();
-    // socket.close().await.unwrap();
+    let uid = claims.id;
+    let (sender, mut receiver) = socket.split();
     insert(state.clone(), uid, uuid.clone());
-    let (s1, mut r1) = mpsc::unbounded_channel::<Arc<event::WsRequest>>();
-    insert_sender(state.clone(), uuid.clone(), s1);
-
-    let (s2, mut r2) = mpsc::unbounded_channel::<SocketMsg>();
-    let s21 = s2.clone();
-    let task1 = tokio::spawn(async move {
-        while let Some(msg) = r1.recv().await {
-            if let Err(e) =  s21.send(SocketMsg::Msg(msg.clone())) {
-                info!(" {} sent message {:#?} error: {}", who, msg.clone(), e.to_string());
-                break;
-            }
-        }
+    let socket_config = state.config.get();
+    let (s1, r1) = queue::bounded_keeping::<Arc<event::WsRequest>>(
+        socket_config.server.socket_queue,
+        socket_config.server.socket_overflow,
+        |msg| !msg.droppable(),
+    );
+    let (s2, r2) = mpsc::channel::<SocketMsg>(CONTROL_QUEUE);
+    let (claims, claims_rx) = watch::channel(claims);
+    let now = chrono::Utc::now().timestamp();
+    let session = Arc::new(SessionInfo {
+        uid,
+        user_agent,
+        addr: who,
+        connected_at: now,
+        last_activity: AtomicI64::new(now),
+        claims: claims.subscribe(),
     });
+    state.insert_user_peer_map(
+        uuid.clone(),
+        Peer {
+            sender: s1.clone(),
+            control: s2.clone(),
+            session: session.clone(),
+        },
+    );
+    let guard = tokio::spawn(guard_session(
+        state.app.clone(),
+        claims_rx,
+        s1.clone(),
+        s2.clone(),
+    ));
+
     let s22 = s2.clone();
 
     let task2 = tokio::spawn(async move {
         let mut ticker = tokio::time::interval(Duration::from_millis(30 * 1000));
         loop {
             ticker.tick().await;
-            if let Err(e) = s22.send(SocketMsg::Ping) {
-                    info!(" {} sent ping error: {:#?}", who, e.to_string());
-                    break;
-                }
-            }
-    });
-
-    let task3 = tokio::spawn(async move {
-        while let Some(msg) = r2.recv().await {
-            match msg {
-                SocketMsg::Close => {
-                    sender.close().await.unwrap();
-                    break;
-                }
-                SocketMsg::Ping => {
-                    sender.send(Message::Ping(vec![1, 2, 3])).await.unwrap();
-                }
-                SocketMsg::Msg(msg) => sender
-                    .send(Message::Text(
-                        serde_json::to_string(&msg.clone().as_ref()).unwrap(),
-                    ))
-                    .await
-                    .map_or_else(
-                        |e| {
-                            info!(" {} sent message error: {:#?}", who, e.to_string());
-                        },
-                        |_| {},
-                    ),
+            // A full queue means the writer is busy, the ping can wait.
+            if let Err(TrySendError::Closed(_)) = s22.try_send(SocketMsg::Ping) {
+                info!(" {} sent ping error: channel closed", who);
+                break;
             }
         }
     });
 
+    let shutdown = state.shutdown.clone();
+    let mut task3 = tokio::spawn(write_socket(sender, r1, r2, shutdown, who, uid));
+
     let state1 = state.clone();
-    let uuid1 = uuid.clone();
+    let conn = Connection {
+        uid,
+        uuid: uuid.clone(),
+        who,
+        claims,
+        reply: s1,
+        session,
+    };
 
-    let task = tokio::spawn(async move {
+    let mut task = tokio::spawn(async move {
         let state = state1.clone();
         while let Some(Ok(msg)) = receiver.next().await {
-            if process_message(state.sender.clone(), uuid1.clone(), msg, who)
-                .await
-                .is_break()
-            {
+            if process_message(&state, &conn, msg).await.is_break() {
                 break;
             }
         }
     });
-    tokio::join!(task).0.map_or_else(
-        |e| {
-            task1.abort();
-            task2.abort();
-            task3.abort();
-            info!(" {} close message error: {:#?}", who, e.to_string());
-        },
-        |_| {
-            info!(" {} close message success", who);
-            s2.send(SocketMsg::Close).unwrap();
-            state.remove_user_peer_map(uuid.clone());
-            state.remove_user_uuid_map(uid, uuid);
-            task1.abort();
-            task2.abort();
-            task3.abort();
+    tokio::select! {
+        res = &mut task => match res {
+            Ok(_) => info!(" {} close message success", who),
+            Err(e) => info!(" {} close message error: {:#?}", who, e.to_string()),
         },
-    );
+        // The writer only stops on its own when the server closed the socket.
+        _ = &mut task3 => {
+            info!(" {} closed by server", who);
+            task.abort();
+        }
+    }
+    state.remove_user_peer_map(uuid.clone());
+    let cancelled = state.cancel_requests(&uuid);
+    if cancelled > 0 {
+        info!(" {} closed, cancelled {} requests", who, cancelled);
+    }
+    state.remove_user_uuid_map(uid, uuid);
+    let _ = s2.try_send(SocketMsg::Close(None));
+    task2.abort();
+    guard.abort();
+}
+
+/// Writes events and control frames to the socket until it is closed. Control
+/// frames go first, events in the order they were queued. A server shutdown
+/// writes what is left of the queue, a `serverRestarting` notice and a close
+/// frame. An overflowed [`queue::Overflow::Disconnect`] queue closes the socket.
+async fn write_socket(
+    mut sender: SplitSink<WebSocket, Message>,
+    mut events: queue::Receiver<Arc<event::WsRequest>>,
+    mut control: mpsc::Receiver<SocketMsg>,
+    shutdown: Shutdown,
+    who: SocketAddr,
+    uid: u64,
+) {
+    let frame = loop {
+        tokio::select! {
+            biased;
+            msg = control.recv() => match msg {
+                Some(SocketMsg::Close(frame)) => break frame,
+                Some(SocketMsg::Ping) => {
+                    if sender.send(Message::Ping(vec![1, 2, 3])).await.is_err() {
+                        return;
+                    }
+                }
+                None => break None,
+            },
+            _ = shutdown.drained() => {
+                // Replies of the drained requests go out before the notice.
+                while let Some(msg) = events.try_recv() {
+                    write_event(&mut sender, who, &msg).await;
+                }
+                let notice = event::WsResponse {
+                    event: event::Event::ServerRestarting("server restarting".into()),
+                    event_type: event::EventType::ServerRestarting,
+                    msg_id: Uuid::new_v4().to_string(),
+                    from: 0,
+                    to: uid,
+                    reply_msg_id: None,
+                };
+                write_event(&mut sender, who, &notice).await;
+                break Some(CloseFrame {
+                    code: CLOSE_SERVICE_RESTART,
+                    reason: "server restarting".into(),
+                });
+            }
+            msg = events.recv() => match msg {
+                Some(msg) => write_event(&mut sender, who, &msg).await,
+                None if events.overflowed() => {
+                    info!(" {} reads too slowly, disconnecting", who);
+                    break Some(CloseFrame {
+                        code: CLOSE_SLOW_CONSUMER,
+                        reason: "too slow".into(),
+                    });
+                }
+                None => break None,
+            },
+        }
+    };
+    if frame.is_some() {
+        let _ = sender.send(Message::Close(frame)).await;
+    }
+    let _ = sender.close().await;
+}
+
+async fn write_event(
+    sender: &mut SplitSink<WebSocket, Message>,
+    who: SocketAddr,
+    msg: &event::WsRequest,
+) {
+    let text = serde_json::to_string(msg).unwrap();
+    if let Err(e) = sender.send(Message::Text(text)).await {
+        info!(" {} sent message error: {:#?}", who, e.to_string());
+    }
+}
+
+/// Swaps the claims of the socket for those of a fresh access token of the
+/// same user.
+fn reauthenticate(state: &WsState, conn: &Connection, msg: &event::WsRequest) {
+    let event::Event::Auth(token) = &msg.event else {
+        return;
+    };
+    let claims = match socket_claims(&state.app, token) {
+        Ok(claims) if claims.id == conn.uid => claims,
+        result => {
+            info!(" {} re-authentication failed for user {}", conn.who, conn.uid);
+            let reason = result.map_or_else(|e| e.to_string(), |_| "token of another user".into());
+            conn.audit(AuditKind::WsAuth, Outcome::Failure)
+                .detail(format!("re-authentication: {reason}"))
+                .record(&state.app.audit);
+            let error = ErrorDetail::new(ErrorCode::AuthFailed, "re-authentication failed");
+            return reply_error(conn, msg, error);
+        }
+    };
+    let exp = claims.exp;
+    conn.claims.send_replace(claims);
+    info!(" {} re-authenticated user {}", conn.who, conn.uid);
+    conn.audit(AuditKind::WsAuth, Outcome::Success)
+        .detail("re-authentication")
+        .record(&state.app.audit);
+    let _ = conn.reply.send(Arc::new(event::WsResponse {
+        event: event::Event::Authenticated(exp),
+        event_type: event::EventType::Authenticated,
+        msg_id: Uuid::new_v4().to_string(),
+        from: 0,
+        to: conn.uid,
+        reply_msg_id: Some(msg.msg_id.clone()),
+    }));
+}
+
+/// Cancels the request of this socket named by `msg` and confirms it with
+/// `cancelled` and `Loading(false)` for the cancelled request.
+fn cancel(state: &WsState, conn: &Connection, msg: &event::WsRequest) {
+    let event::Event::Cancel(msg_id) = &msg.event else {
+        return;
+    };
+    if !state.cancel_request(&conn.uuid, msg_id) {
+        let error = ErrorDetail::new(ErrorCode::UnknownRequest, "no such request in flight");
+        return reply_error(conn, msg, error);
+    }
+    info!(" {} cancelled request {}", conn.who, msg_id);
+    let _ = conn.reply.send(Arc::new(event::WsResponse {
+        event: event::Event::Cancelled(msg_id.clone()),
+        event_type: event::EventType::Cancelled,
+        msg_id: Uuid::new_v4().to_string(),
+        from: 0,
+        to: conn.uid,
+        reply_msg_id: Some(msg.msg_id.clone()),
+    }));
+    let loading = event::WsResponse::loading(conn.uid, msg_id, false);
+    let _ = conn.reply.send(Arc::new(loading));
+}
+
+/// Overwrites the client supplied `from` with the uid the socket authenticated
+/// as. `0` means the client left it unset, anything else that differs is an
+/// impersonation attempt.
+fn stamp_sender(state: &WsState, conn: &Connection, msg: &mut event::WsRequest) {
+    if msg.from != conn.uid && msg.from != 0 {
+        conn.audit(AuditKind::ForgedSender, Outcome::Failure)
+            .detail(format!("message {} as user {}", msg.msg_id, msg.from))
+            .record(&state.app.audit);
+    }
+    msg.from = conn.uid;
+}
+
+/// Checks the role of the socket against the event type and target of `msg`.
+/// Requests to the server need a handler and the role it requires.
+fn authorize(
+    state: &WsState,
+    conn: &Connection,
+    msg: &event::WsRequest,
+) -> Result<(), ErrorDetail> {
+    let role = conn.claims.borrow().role;
+    let required = if msg.to == 0 {
+        let handler = state.handlers.get(&msg.event_type);
+        handler.map(|handler| handler.required_role())
+    } else {
+        Some(msg.event_type.required_role())
+    };
+    let (code, reason) = match required {
+        None => (ErrorCode::UnknownEvent, "unknown event type"),
+        Some(required) if !role.allows(required) => (ErrorCode::Forbidden, "forbidden event type"),
+        _ if msg.to == event::BROADCAST && !role.allows(Role::Admin) => {
+            (ErrorCode::Forbidden, "only administrators can broadcast")
+        }
+        _ => return Ok(()),
+    };
+    conn.audit(AuditKind::Denied, Outcome::Failure)
+        .detail(format!(
+            "{:?} sent {:?} to {}: {}",
+            role, msg.event_type, msg.to, reason
+        ))
+        .record(&state.app.audit);
+    Err(ErrorDetail::new(code, reason))
+}
+
+/// Answers `msg` with `error` and `Loading(false)`.
+fn reply_error(conn: &Connection, msg: &event::WsRequest, error: ErrorDetail) {
+    for resp in event::WsResponse::failure(conn.uid, &msg.msg_id, error) {
+        let _ = conn.reply.send(Arc::new(resp));
+    }
 }
 
 async fn process_message(
-    s: Sender<event::ChannelMessage>,
-    uuid: Arc<Uuid>,
+    state: &WsState,
+    conn: &Connection,
     msg: Message,
-    who: SocketAddr,
 ) -> ControlFlow<(), ()> {
+    let who = conn.who;
+    let uuid = conn.uuid.clone();
+    if matches!(msg, Message::Text(_) | Message::Binary(_)) {
+        let now = chrono::Utc::now().timestamp();
+        conn.session.last_activity.store(now, Ordering::Relaxed);
+    }
     match msg {
         Message::Text(t) => match serde_json::from_str::<event::WsRequest>(&t) {
-            Ok(msg) => {
-                info!(" {} sent message: {:?}", who, msg);
-                s.send(event::ChannelMessage { uuid, body: msg })
-                    .map_err(|e| {
-                        info!(" {} sent message error: {:#?}", who, e.to_string());
-                    })
-                    .unwrap();
+            Ok(msg) if matches!(msg.event, event::Event::Auth(_)) => {
+                reauthenticate(state, conn, &msg)
+            }
+            Ok(msg) if matches!(msg.event, event::Event::Cancel(_)) => cancel(state, conn, &msg),
+            Ok(mut msg) => {
+                info!(" {} sent message: {}", who, redact_tokens(&format!("{:?}", msg)));
+                stamp_sender(state, conn, &mut msg);
+                if let Err(error) = authorize(state, conn, &msg) {
+                    reply_error(conn, &msg, error);
+                    return ControlFlow::Continue(());
+                }
+                // Tracked from here so it can be cancelled while it waits.
+                let tracked = msg.to == 0;
+                if tracked && !state.queue_request(uuid.clone(), msg.msg_id.clone()) {
+                    let error = ErrorDetail::new(ErrorCode::BadRequest, "msgId already in flight");
+                    reply_error(conn, &msg, error);
+                    return ControlFlow::Continue(());
+                }
+                let msg = event::ChannelMessage {
+                    uuid,
+                    uid: conn.uid,
+                    body: msg,
+                };
+                match state.sender.try_send(msg) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(msg)) => {
+                        info!(" {} sent message while the dispatch queue is full", who);
+                        if tracked {
+                            state.finish_request(&msg.uuid, &msg.body.msg_id);
+                        }
+                        let error = ErrorDetail::new(ErrorCode::Busy, "server busy");
+                        reply_error(conn, &msg.body, error);
+                    }
+                    Err(TrySendError::Closed(_)) => {
+                        info!(" {} sent message error: channel closed", who);
+                        return ControlFlow::Break(());
+                    }
+                }
             }
             Err(_e) => {
-                info!(" {} sent unknown message: {:?}", who, t);
+                info!(" {} sent unknown message: {}", who, redact_tokens(&t));
             }
         },
         Message::Binary(d) => {
-            info!(" {} sent {} bytes: {:?}", who, d.len(), d);
+            info!(" {} sent {} bytes", who, d.len());
         }
         Message::Close(c) => {
             if let Some(cf) = c {
@@ -1,27 +1,53 @@
 use std::{
-    collections::HashMap,
+    collections::{hash_map::Entry, HashMap},
     sync::{Arc, Mutex},
 };
 
+use axum::extract::ws::CloseFrame;
+use tokio::task::JoinHandle;
 use uuid::Uuid;
 
-use super::{UserPeerMap, UserUUidMap};
-use crate::utils::event;
-
-type Sender<T> = tokio::sync::mpsc::UnboundedSender<T>;
+use super::{Outbox, Peer, RequestMap, SessionInfo, Sender, SocketMsg, UserPeerMap, UserUUidMap};
+use crate::{
+    auth::oidc::{Oidc, OidcConfig},
+    config::SharedConfig,
+    handlers::Registry,
+    server::shutdown::Shutdown,
+    state::AppState,
+    utils::event,
+};
 
 pub struct WsState {
+    pub app: Arc<AppState>,
+    pub config: Arc<SharedConfig>,
+    /// Requests for the channel, bounded by `server.dispatch_queue`.
     pub sender: Sender<event::ChannelMessage>,
     pub user_peer_map: UserPeerMap,
     pub user_uuid_map: UserUUidMap,
+    /// Single sign-on, `None` unless `oidc.issuer` is configured.
+    pub oidc: Option<Oidc>,
+    pub shutdown: Shutdown,
+    /// Handlers of requests to the server, by event type.
+    pub handlers: Registry,
+    requests: RequestMap,
 }
 
 impl WsState {
-    pub fn new(sender: Sender<event::ChannelMessage>) -> Self {
+    pub fn new(
+        app: Arc<AppState>,
+        sender: Sender<event::ChannelMessage>,
+        shutdown: Shutdown,
+    ) -> Self {
         Self {
+            oidc: OidcConfig::new(&app.config.get().oidc).map(Oidc::new),
+            config: app.config.clone(),
+            app,
             sender,
             user_peer_map: Arc::new(Mutex::new(HashMap::new())),
             user_uuid_map: Arc::new(Mutex::new(HashMap::new())),
+            shutdown,
+            handlers: Registry::builtin(),
+            requests: Mutex::new(HashMap::new()),
         }
     }
 
@@ -34,32 +60,305 @@ impl WsState {
         }
     }
 
-    pub fn insert_user_peer_map(&self, uuid: Arc<Uuid>, sender: Sender<Arc<event::WsRequest>>) {
+    pub fn insert_user_peer_map(&self, uuid: Arc<Uuid>, peer: Peer) {
         let mut map = self
             .user_peer_map
             .lock()
             .expect("lock user_peer_map failed");
-        map.insert(uuid, sender);
+        map.insert(uuid, peer);
     }
 
-    #[allow(dead_code)]
     pub fn remove_user_peer_map(&self, uuid: Arc<Uuid>) {
         self.user_peer_map.lock().unwrap().remove(&uuid);
     }
 
-    #[allow(dead_code)]
     pub fn remove_user_uuid_map(&self, uid: u64, uuid: Arc<Uuid>) {
         let mut user_uuid_map = self.user_uuid_map.lock().unwrap();
         if let Some(uuids) = user_uuid_map.get_mut(&uid) {
             uuids.retain(|x| x != &uuid);
+            if uuids.is_empty() {
+                user_uuid_map.remove(&uid);
+            }
         }
     }
 
+    pub fn all_uuids(&self) -> Vec<Arc<Uuid>> {
+        self.user_peer_map.lock().unwrap().keys().cloned().collect()
+    }
+
     pub fn get_user_uuid_map(&self, uid: u64) -> Option<Vec<Arc<Uuid>>> {
         self.user_uuid_map.lock().unwrap().get(&uid).cloned()
     }
 
-    pub fn get_user_peer_map(&self, uuid: Arc<Uuid>) -> Option<Sender<Arc<event::WsRequest>>> {
-        self.user_peer_map.lock().unwrap().get(&uuid).cloned()
+    pub fn get_user_peer_map(&self, uuid: Arc<Uuid>) -> Option<Outbox> {
+        self.user_peer_map
+            .lock()
+            .unwrap()
+            .get(&uuid)
+            .map(|peer| peer.sender.clone())
+    }
+
+    /// Asks every socket of `uid` to close with `code`, or only those signed
+    /// in with token family `fid` when given. Returns the number of sockets
+    /// notified.
+    pub fn close_user_sessions(
+        &self,
+        uid: u64,
+        fid: Option<&str>,
+        code: u16,
+        reason: &'static str,
+    ) -> usize {
+        let uuids = self.get_user_uuid_map(uid).unwrap_or_default();
+        let peers = self.user_peer_map.lock().unwrap();
+        uuids
+            .iter()
+            .filter_map(|uuid| peers.get(uuid))
+            .filter(|peer| fid.is_none_or(|fid| peer.session.claims.borrow().fid == fid))
+            .filter(|peer| {
+                peer.control
+                    .try_send(SocketMsg::Close(Some(CloseFrame {
+                        code,
+                        reason: reason.into(),
+                    })))
+                    .is_ok()
+            })
+            .count()
+    }
+
+    /// Sockets of `uid`.
+    pub fn sessions(&self, uid: u64) -> Vec<(Arc<Uuid>, Peer)> {
+        let uuids = self.get_user_uuid_map(uid).unwrap_or_default();
+        let peers = self.user_peer_map.lock().unwrap();
+        uuids
+            .into_iter()
+            .filter_map(|uuid| {
+                let peer = peers.get(&uuid)?.clone();
+                Some((uuid, peer))
+            })
+            .collect()
+    }
+
+    /// Every socket of every user.
+    pub fn all_sessions(&self) -> Vec<(Arc<Uuid>, Peer)> {
+        let peers = self.user_peer_map.lock().unwrap();
+        peers
+            .iter()
+            .map(|(uuid, peer)| (uuid.clone(), peer.clone()))
+            .collect()
+    }
+
+    /// Asks socket `uuid` to close if it belongs to `uid`, and returns its
+    /// session.
+    pub fn close_session(
+        &self,
+        uid: u64,
+        uuid: &Uuid,
+        code: u16,
+        reason: &'static str,
+    ) -> Option<Arc<SessionInfo>> {
+        let peers = self.user_peer_map.lock().unwrap();
+        let peer = peers.get(uuid).filter(|peer| peer.session.uid == uid)?;
+        let _ = peer.control.try_send(SocketMsg::Close(Some(CloseFrame {
+            code,
+            reason: reason.into(),
+        })));
+        Some(peer.session.clone())
+    }
+
+    /// Records a request of socket `uuid` waiting for the dispatcher. Returns
+    /// `false`, leaving the earlier one alone, if `msg_id` is in flight.
+    pub fn queue_request(&self, uuid: Arc<Uuid>, msg_id: String) -> bool {
+        let mut requests = self.requests.lock().unwrap();
+        match requests.entry((uuid, msg_id)) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(None);
+                true
+            }
+        }
+    }
+
+    /// Spawns a queued request with `start`, unless it was cancelled while
+    /// waiting.
+    pub fn start_request<T>(
+        &self,
+        uuid: &Arc<Uuid>,
+        msg_id: &str,
+        start: impl FnOnce() -> JoinHandle<T>,
+    ) -> Option<JoinHandle<T>> {
+        let mut requests = self.requests.lock().unwrap();
+        let task = requests.get_mut(&(uuid.clone(), msg_id.to_string()))?;
+        let handle = start();
+        *task = Some(handle.abort_handle());
+        Some(handle)
+    }
+
+    /// Forgets a request. Returns `false` if it was cancelled, then nothing
+    /// more may be sent for it.
+    pub fn finish_request(&self, uuid: &Arc<Uuid>, msg_id: &str) -> bool {
+        let key = (uuid.clone(), msg_id.to_string());
+        self.requests.lock().unwrap().remove(&key).is_some()
+    }
+
+    /// Drops a waiting request or aborts a running one. Returns `false` if
+    /// there is none, it already finished or never existed.
+    pub fn cancel_request(&self, uuid: &Arc<Uuid>, msg_id: &str) -> bool {
+        let key = (uuid.clone(), msg_id.to_string());
+        let Some(task) = self.requests.lock().unwrap().remove(&key) else {
+            return false;
+        };
+        if let Some(task) = task {
+            task.abort();
+        }
+        true
+    }
+
+    /// Forgets every request that has not started yet and returns them, for
+    /// a dispatcher that crashed with them queued.
+    pub fn take_waiting_requests(&self) -> Vec<(Arc<Uuid>, String)> {
+        let mut requests = self.requests.lock().unwrap();
+        let mut waiting = Vec::new();
+        requests.retain(|(uuid, msg_id), task| {
+            if task.is_some() {
+                return true;
+            }
+            waiting.push((uuid.clone(), msg_id.clone()));
+            false
+        });
+        waiting
+    }
+
+    /// Cancels every request of socket `uuid`, once it closed.
+    pub fn cancel_requests(&self, uuid: &Uuid) -> usize {
+        let mut requests = self.requests.lock().unwrap();
+        let before = requests.len();
+        requests.retain(|(id, _), task| {
+            if id.as_ref() != uuid {
+                return true;
+            }
+            if let Some(task) = task.take() {
+                task.abort();
+            }
+            false
+        });
+        before - requests.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{future::pending, sync::atomic::AtomicI64};
+
+    use tokio::sync::{mpsc, watch};
+
+    use super::*;
+    use crate::{
+        auth::{JWTData, Role},
+        utils::queue::{self, Overflow},
+        ws::CONTROL_QUEUE,
+    };
+
+    fn state() -> WsState {
+        let (sender, _) = tokio::sync::mpsc::channel(1);
+        WsState::new(Arc::new(AppState::in_temp_dir()), sender, Shutdown::new())
+    }
+
+    /// Registers a socket of user 1 signed in with token family `fid`.
+    /// Returns what reaches its writer.
+    fn connect(state: &WsState, fid: &str) -> mpsc::Receiver<SocketMsg> {
+        let uuid = Arc::new(Uuid::new_v4());
+        let claims = JWTData {
+            name: "ann".into(),
+            id: 1,
+            exp: 0,
+            jti: Uuid::new_v4().to_string(),
+            fid: fid.into(),
+            role: Role::Parent,
+            scopes: None,
+        };
+        let (sender, _) = queue::bounded(1, Overflow::Disconnect);
+        let (control, writer) = mpsc::channel(CONTROL_QUEUE);
+        let session = Arc::new(SessionInfo {
+            uid: 1,
+            user_agent: String::new(),
+            addr: "127.0.0.1:4000".parse().unwrap(),
+            connected_at: 0,
+            last_activity: AtomicI64::new(0),
+            claims: watch::channel(claims).1,
+        });
+        state.insert_user_uuid_map(1, uuid.clone());
+        state.insert_user_peer_map(
+            uuid,
+            Peer {
+                sender,
+                control,
+                session,
+            },
+        );
+        writer
+    }
+
+    #[test]
+    fn test_close_user_sessions_of_one_family() {
+        let state = state();
+        let mut first = connect(&state, "a");
+        let mut second = connect(&state, "b");
+
+        assert_eq!(state.close_user_sessions(1, Some("a"), 4001, "logged out"), 1);
+        assert!(matches!(first.try_recv(), Ok(SocketMsg::Close(_))));
+        assert!(second.try_recv().is_err());
+
+        assert_eq!(state.close_user_sessions(1, None, 4001, "logged out"), 2);
+        assert!(matches!(second.try_recv(), Ok(SocketMsg::Close(_))));
+    }
+
+    #[tokio::test]
+    async fn test_cancel_by_msg_id() {
+        let state = state();
+        let uuid = Arc::new(Uuid::new_v4());
+        assert!(state.queue_request(uuid.clone(), "a".into()));
+        assert!(!state.queue_request(uuid.clone(), "a".into()));
+        assert!(state.queue_request(uuid.clone(), "b".into()));
+
+        // A waiting request never starts.
+        assert!(state.cancel_request(&uuid, "a"));
+        assert!(state.start_request(&uuid, "a", || tokio::spawn(async {})).is_none());
+        // A running one is aborted.
+        let running = state.start_request(&uuid, "b", || tokio::spawn(pending::<()>()));
+        assert!(state.cancel_request(&uuid, "b"));
+        assert!(running.unwrap().await.unwrap_err().is_cancelled());
+        assert!(!state.cancel_request(&uuid, "b"));
+        assert!(!state.finish_request(&uuid, "b"));
+
+        assert!(state.queue_request(uuid.clone(), "a".into()));
+    }
+
+    #[tokio::test]
+    async fn test_socket_close_cancels_its_requests() {
+        let state = state();
+        let (closed, open) = (Arc::new(Uuid::new_v4()), Arc::new(Uuid::new_v4()));
+        state.queue_request(closed.clone(), "a".into());
+        state.queue_request(closed.clone(), "b".into());
+        state.queue_request(open.clone(), "a".into());
+        let running = state.start_request(&closed, "b", || tokio::spawn(pending::<()>()));
+
+        assert_eq!(state.cancel_requests(&closed), 2);
+        assert!(running.unwrap().await.unwrap_err().is_cancelled());
+        assert!(!state.finish_request(&closed, "a"));
+        assert!(state.finish_request(&open, "a"));
+    }
+
+    #[tokio::test]
+    async fn test_take_waiting_requests_leaves_running_ones() {
+        let state = state();
+        let uuid = Arc::new(Uuid::new_v4());
+        state.queue_request(uuid.clone(), "a".into());
+        state.queue_request(uuid.clone(), "b".into());
+        let running = state.start_request(&uuid, "b", || tokio::spawn(pending::<()>()));
+
+        assert_eq!(state.take_waiting_requests(), [(uuid.clone(), "a".to_owned())]);
+        assert!(state.start_request(&uuid, "a", || tokio::spawn(async {})).is_none());
+        assert!(state.cancel_request(&uuid, "b"));
+        assert!(running.unwrap().await.unwrap_err().is_cancelled());
     }
 }